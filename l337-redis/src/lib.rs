@@ -1,21 +1,20 @@
-//! Postgres adapater for l3-37 pool
+//! Redis adapater for l3-37 pool
 // #![deny(missing_docs, missing_debug_implementations)]
 
-extern crate futures;
-pub extern crate l337;
-extern crate redis;
-extern crate tokio;
+pub use l337;
+pub use redis;
 
-use futures::Future;
-use redis::aio::{ConnectionLike, SharedConnection};
-use redis::{Client, IntoConnectionInfo, RedisError};
+use async_trait::async_trait;
+use redis::aio::MultiplexedConnection;
+use redis::{Client, ConnectionLike, IntoConnectionInfo, RedisError};
 
 type Result<T> = std::result::Result<T, RedisError>;
 
-/// A `ManageConnection` for `RedisConnections`s.
+/// A `ManageConnection` for `MultiplexedConnection`s.
 #[derive(Debug)]
 pub struct RedisConnectionManager {
     client: redis::Client,
+    recycle: bool,
 }
 
 impl RedisConnectionManager {
@@ -23,79 +22,59 @@ impl RedisConnectionManager {
     pub fn new(params: impl IntoConnectionInfo) -> Result<RedisConnectionManager> {
         Ok(RedisConnectionManager {
             client: Client::open(params)?,
+            recycle: false,
         })
     }
-}
-
-/// Unfortunately a required struct to make the redis-rs api work.
-/// As a todo, we might want to consider improving the redis-rs api or switching to a different lib
-/// Looking at the tests will make why this struct is needed clear, but basically it requires
-/// an owned version of conn so we can't use deref to make everything work nice.
-pub struct AsyncConnection(pub l337::Conn<RedisConnectionManager>);
-
-impl ConnectionLike for AsyncConnection {
-    fn req_packed_command(
-        self,
-        cmd: Vec<u8>,
-    ) -> Box<Future<Item = (Self, redis::Value), Error = RedisError> + Send> {
-        let conn = self.0.clone();
-        Box::new(
-            conn.req_packed_command(cmd)
-                .map(move |(_conn, value)| (self, value)),
-        )
-    }
-
-    fn req_packed_commands(
-        self,
-        cmd: Vec<u8>,
-        offset: usize,
-        count: usize,
-    ) -> Box<Future<Item = (Self, Vec<redis::Value>), Error = RedisError> + Send> {
-        let conn = self.0.clone();
-        Box::new(
-            conn.req_packed_commands(cmd, offset, count)
-                .map(move |(_conn, value)| (self, value)),
-        )
-    }
 
-    fn get_db(&self) -> i64 {
-        self.0.get_db()
+    /// Switch back to the configured database when a connection is returned to the pool, in case
+    /// it was used to `SELECT` another one. Off by default, since it costs a round trip to redis
+    /// on every checkin.
+    pub fn with_recycle(mut self, recycle: bool) -> Self {
+        self.recycle = recycle;
+        self
     }
 }
 
+#[async_trait]
 impl l337::ManageConnection for RedisConnectionManager {
-    type Connection = SharedConnection;
+    type Connection = MultiplexedConnection;
     type Error = RedisError;
 
-    fn connect(
-        &self,
-    ) -> Box<Future<Item = Self::Connection, Error = l337::Error<Self::Error>> + 'static + Send>
-    {
-        Box::new(
-            self.client
-                .get_shared_async_connection()
-                .map_err(l337::Error::External),
-        )
+    async fn connect(&self) -> std::result::Result<Self::Connection, l337::Error<Self::Error>> {
+        self.client
+            .get_multiplexed_tokio_connection()
+            .await
+            .map_err(l337::Error::External)
     }
 
-    fn is_valid(
+    async fn is_valid(
         &self,
-        conn: Self::Connection,
-    ) -> Box<Future<Item = (), Error = l337::Error<Self::Error>>> {
-        Box::new(
-            redis::cmd("PING")
-                .query_async::<_, ()>(conn)
-                .map(|_| ())
-                .map_err(|e| l337::Error::External(e)),
-        )
+        conn: &mut Self::Connection,
+    ) -> std::result::Result<(), l337::Error<Self::Error>> {
+        redis::cmd("PING")
+            .query_async::<_, ()>(conn)
+            .await
+            .map_err(l337::Error::External)
     }
 
     fn has_broken(&self, _conn: &mut Self::Connection) -> bool {
         false
     }
 
-    fn timed_out(&self) -> l337::Error<Self::Error> {
-        unimplemented!()
+    async fn recycle(
+        &self,
+        conn: &mut Self::Connection,
+    ) -> std::result::Result<(), l337::Error<Self::Error>> {
+        if !self.recycle {
+            return Ok(());
+        }
+
+        // Switch back to the configured database, in case the last user selected another one
+        redis::cmd("SELECT")
+            .arg(self.client.get_db())
+            .query_async::<_, ()>(conn)
+            .await
+            .map_err(l337::Error::External)
     }
 }
 
@@ -112,15 +91,14 @@ mod tests {
         let mut runtime = Runtime::new().expect("could not run");
         let config: Config = Default::default();
 
-        let future = Pool::new(mngr, config).and_then(|pool| {
-            pool.connection().and_then(|conn| {
-                redis::cmd("PING")
-                    .query_async::<_, ()>(AsyncConnection(conn))
-                    .map(|_| println!("done ping"))
-                    .map_err(|e| l337::Error::External(e))
-            })
+        runtime.block_on(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let mut conn = pool.connection().await.unwrap();
+            redis::cmd("PING")
+                .query_async::<_, ()>(&mut *conn)
+                .await
+                .unwrap();
+            println!("done ping");
         });
-
-        runtime.block_on(future).expect("could not run");
     }
 }
@@ -0,0 +1,419 @@
+//! Configuration for a pool, and a builder that validates it.
+
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::ConfigError;
+use crate::observer::PoolObserver;
+
+/// Configuration for the connection pool
+///
+/// `Config::builder` checks the settings as it builds, and is the way to go when they come from
+/// user input.
+///
+/// With the `serde` feature, a config can be deserialized (e.g. from TOML, YAML or JSON) with
+/// durations written like `"30s"` or `"1m 30s"`. Missing fields keep their default, and unknown
+/// fields are an error (except with `Config::from_env`).
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct Config {
+    /// Minimum number of connections in the pool. The pool will be initialied with this number of
+    /// connections
+    pub min_size: usize,
+    /// Max number of connections to keep in the pool
+    pub max_size: usize,
+    /// How long `Pool::connection` will wait for a connection before giving up and returning the
+    /// manager's `timed_out` error. `None` waits forever.
+    #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+    pub connection_timeout: Option<Duration>,
+    /// Maximum lifetime of a connection. Connections older than this are closed instead of being
+    /// handed out or returned to the pool, and idle ones are closed by the background maintenance
+    /// task. Closed connections are replaced to keep the pool at `min_size`. `None` keeps
+    /// connections forever.
+    #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+    pub max_lifetime: Option<Duration>,
+    /// Up to this much is randomly taken off of each connection's `max_lifetime`, so connections
+    /// made at the same time don't all reconnect at the same time.
+    #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+    pub max_lifetime_jitter: Option<Duration>,
+    /// Idle connections are closed after being idle for this long, as long as that doesn't take
+    /// the pool below `min_size`. `None` keeps idle connections forever.
+    #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+    pub idle_timeout: Option<Duration>,
+    /// Whether `Pool::connection` should check idle connections with `ManageConnection::is_valid`
+    /// before handing them out. Connections that fail are closed, and another one is tried.
+    pub test_on_checkout: bool,
+    /// With `test_on_checkout`, connections that were last used less than this long ago are handed
+    /// out without being checked. `None` checks every idle connection.
+    #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+    pub validation_interval: Option<Duration>,
+    /// Idle connections that go this long without being used or validated are checked in the
+    /// background with `ManageConnection::is_valid`. Connections that fail are closed and replaced.
+    /// `None` disables background health checks.
+    #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+    pub health_check_interval: Option<Duration>,
+    /// How long a background health check may take before the connection is considered to have
+    /// failed it, e.g. because a firewall silently dropped it and the check would otherwise wait for
+    /// TCP to give up.
+    #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+    pub health_check_timeout: Duration,
+    /// How often the background maintenance task (e.g. closing idle connections) runs.
+    #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+    pub maintenance_interval: Duration,
+    /// Receives callbacks about what the pool is doing, e.g. to record metrics.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub observer: Option<Arc<dyn PoolObserver>>,
+    /// Name of the pool, used to tell pools apart in metrics.
+    pub name: Option<String>,
+    /// Which idle connection `Pool::connection` hands out first.
+    pub idle_order: IdleOrder,
+    /// Maximum number of calls to `Pool::connection` that can be waiting for a connection at once.
+    /// Calls beyond this fail straight away with `InternalError::TooManyWaiters`. `None` doesn't
+    /// limit waiters.
+    pub max_waiters: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_size: 10,
+            min_size: 1,
+            connection_timeout: None,
+            max_lifetime: None,
+            max_lifetime_jitter: None,
+            idle_timeout: None,
+            test_on_checkout: false,
+            validation_interval: None,
+            health_check_interval: None,
+            health_check_timeout: Duration::from_secs(10),
+            maintenance_interval: Duration::from_secs(30),
+            observer: None,
+            name: None,
+            max_waiters: None,
+            idle_order: IdleOrder::default(),
+        }
+    }
+}
+
+/// The order idle connections are handed out in, see `Config::idle_order`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum IdleOrder {
+    /// The connection that has been idle the longest goes first. This spreads the load across
+    /// every connection (and whatever backends they're connected to).
+    #[default]
+    Fifo,
+    /// The connection that became idle most recently goes first. This leaves the other connections
+    /// idle, so they can be closed by `Config::idle_timeout` when the pool is bigger than it needs
+    /// to be.
+    Lifo,
+    /// The connection that was most recently used or validated goes first, since it's the most
+    /// likely to still be good.
+    MostRecentlyValidated,
+}
+
+impl Config {
+    /// Returns a builder, starting from the default config.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder {
+            config: Config::default(),
+        }
+    }
+
+    /// Checks that the settings make sense together. `Pool::new` refuses configs that don't.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_size == 0 {
+            return Err(ConfigError::ZeroMaxSize);
+        }
+        if self.min_size > self.max_size {
+            return Err(ConfigError::MinSizeAboveMaxSize {
+                min_size: self.min_size,
+                max_size: self.max_size,
+            });
+        }
+        if self.maintenance_interval == Duration::from_secs(0) {
+            return Err(ConfigError::ZeroMaintenanceInterval);
+        }
+
+        Ok(())
+    }
+
+    /// Loads a config from `L337_*` environment variables, named after the fields: e.g.
+    /// `L337_MAX_SIZE=20` or `L337_IDLE_TIMEOUT=10m`. Variables that aren't set keep their
+    /// default, and `L337_*` variables that don't name a field are ignored. The config is
+    /// validated, see `Config::validate`.
+    #[cfg(feature = "serde")]
+    pub fn from_env() -> Result<Config, ConfigError> {
+        Config::from_vars(std::env::vars())
+    }
+
+    /// Like `from_env`, but reads the variables from `vars`.
+    #[cfg(feature = "serde")]
+    fn from_vars(vars: impl Iterator<Item = (String, String)>) -> Result<Config, ConfigError> {
+        let fields = field_names();
+        let vars = vars.filter(|(key, _)| match key.strip_prefix("L337_") {
+            Some(field) => fields.contains(&field.to_lowercase().as_str()),
+            None => false,
+        });
+        let config: Config = envy::prefixed("L337_")
+            .from_iter(vars)
+            .map_err(|err| ConfigError::Parse(err.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Lifetime for a new connection: `max_lifetime`, less a random amount of jitter.
+    pub(crate) fn connection_lifetime(&self) -> Option<Duration> {
+        let max_lifetime = self.max_lifetime?;
+        let jitter = match self.max_lifetime_jitter {
+            Some(jitter) => jitter.min(max_lifetime),
+            None => return Some(max_lifetime),
+        };
+        let jitter_nanos = rand::thread_rng().gen_range(0, jitter.as_nanos() as u64 + 1);
+        Some(max_lifetime - Duration::from_nanos(jitter_nanos))
+    }
+}
+
+/// The names of `Config`'s fields, as serde sees them.
+#[cfg(feature = "serde")]
+fn field_names() -> &'static [&'static str] {
+    use serde::de::{self, Deserialize, Visitor};
+
+    /// Deserializer that only records the names of the fields of the struct it's asked for
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de, 'a> de::Deserializer<'de> for FieldNames<'a> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("only field names are recorded"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("only field names are recorded"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+            ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = Config::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+/// Builds a `Config`, see `Config::builder`. Settings that aren't set keep their default.
+#[derive(Debug)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// Sets `Config::min_size`.
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.config.min_size = min_size;
+        self
+    }
+
+    /// Sets `Config::max_size`.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.config.max_size = max_size;
+        self
+    }
+
+    /// Sets `Config::connection_timeout`.
+    pub fn connection_timeout(mut self, connection_timeout: Duration) -> Self {
+        self.config.connection_timeout = Some(connection_timeout);
+        self
+    }
+
+    /// Sets `Config::max_lifetime`.
+    pub fn max_lifetime(mut self, max_lifetime: Duration) -> Self {
+        self.config.max_lifetime = Some(max_lifetime);
+        self
+    }
+
+    /// Sets `Config::max_lifetime_jitter`.
+    pub fn max_lifetime_jitter(mut self, max_lifetime_jitter: Duration) -> Self {
+        self.config.max_lifetime_jitter = Some(max_lifetime_jitter);
+        self
+    }
+
+    /// Sets `Config::idle_timeout`.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.config.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Sets `Config::test_on_checkout`.
+    pub fn test_on_checkout(mut self, test_on_checkout: bool) -> Self {
+        self.config.test_on_checkout = test_on_checkout;
+        self
+    }
+
+    /// Sets `Config::validation_interval`.
+    pub fn validation_interval(mut self, validation_interval: Duration) -> Self {
+        self.config.validation_interval = Some(validation_interval);
+        self
+    }
+
+    /// Sets `Config::health_check_interval`.
+    pub fn health_check_interval(mut self, health_check_interval: Duration) -> Self {
+        self.config.health_check_interval = Some(health_check_interval);
+        self
+    }
+
+    /// Sets `Config::health_check_timeout`.
+    pub fn health_check_timeout(mut self, health_check_timeout: Duration) -> Self {
+        self.config.health_check_timeout = health_check_timeout;
+        self
+    }
+
+    /// Sets `Config::maintenance_interval`.
+    pub fn maintenance_interval(mut self, maintenance_interval: Duration) -> Self {
+        self.config.maintenance_interval = maintenance_interval;
+        self
+    }
+
+    /// Sets `Config::observer`.
+    pub fn observer(mut self, observer: Arc<dyn PoolObserver>) -> Self {
+        self.config.observer = Some(observer);
+        self
+    }
+
+    /// Sets `Config::name`.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.config.name = Some(name.into());
+        self
+    }
+
+    /// Sets `Config::idle_order`.
+    pub fn idle_order(mut self, idle_order: IdleOrder) -> Self {
+        self.config.idle_order = idle_order;
+        self
+    }
+
+    /// Sets `Config::max_waiters`.
+    pub fn max_waiters(mut self, max_waiters: usize) -> Self {
+        self.config.max_waiters = Some(max_waiters);
+        self
+    }
+
+    /// Returns the config, or the reason it's invalid, see `Config::validate`.
+    pub fn build(self) -> Result<Config, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_sets_fields() {
+        let config = Config::builder()
+            .min_size(2)
+            .max_size(4)
+            .connection_timeout(Duration::from_secs(1))
+            .idle_timeout(Duration::from_secs(60))
+            .name("primary")
+            .build()
+            .unwrap();
+        assert_eq!(config.min_size, 2);
+        assert_eq!(config.max_size, 4);
+        assert_eq!(config.connection_timeout, Some(Duration::from_secs(1)));
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(60)));
+        assert_eq!(config.name.as_deref(), Some("primary"));
+        assert_eq!(config.max_lifetime, None);
+    }
+
+    #[test]
+    fn builder_rejects_invalid_configs() {
+        match Config::builder().min_size(5).max_size(2).build() {
+            Err(ConfigError::MinSizeAboveMaxSize {
+                min_size: 5,
+                max_size: 2,
+            }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match Config::builder().min_size(0).max_size(0).build() {
+            Err(ConfigError::ZeroMaxSize) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match Config::builder()
+            .maintenance_interval(Duration::from_secs(0))
+            .build()
+        {
+            Err(ConfigError::ZeroMaintenanceInterval) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn deserializes_with_human_readable_durations() {
+        let config: Config = serde_json::from_str(
+            r#"{"max_size": 20, "idle_timeout": "10m", "maintenance_interval": "1s 500ms", "idle_order": "lifo"}"#,
+        )
+        .unwrap();
+        assert_eq!(config.idle_order, IdleOrder::Lifo);
+        assert_eq!(config.max_size, 20);
+        assert_eq!(config.min_size, 1);
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(600)));
+        assert_eq!(config.maintenance_interval, Duration::from_millis(1500));
+        assert_eq!(config.connection_timeout, None);
+
+        assert!(serde_json::from_str::<Config>(r#"{"max_sise": 20}"#).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn loads_from_env_vars() {
+        let vars = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+                .into_iter()
+        };
+
+        let config = Config::from_vars(vars(&[
+            ("L337_MIN_SIZE", "2"),
+            ("L337_CONNECTION_TIMEOUT", "5s"),
+            ("L337_NAME", "primary"),
+            ("L337_FOO", "bar"),
+            ("PATH", "/usr/bin"),
+        ]))
+        .unwrap();
+        assert_eq!(config.min_size, 2);
+        assert_eq!(config.connection_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(config.name.as_deref(), Some("primary"));
+
+        match Config::from_vars(vars(&[("L337_MIN_SIZE", "20")])) {
+            Err(ConfigError::MinSizeAboveMaxSize { .. }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match Config::from_vars(vars(&[("L337_MAX_SIZE", "lots")])) {
+            Err(ConfigError::Parse(_)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -64,6 +64,34 @@ pub struct Conn<C: ManageConnection> {
     /// Underlying pool. A reference is stored here so we can push the connection back into the
     /// pool on drop
     pub pool: Pool<C>,
+    /// Whether the connection has been marked as broken with `mark_broken`
+    broken: bool,
+}
+
+impl<C: ManageConnection> Conn<C> {
+    /// Wraps a connection checked out of `pool`
+    pub(crate) fn new(conn: Live<C::Connection>, pool: Pool<C>) -> Conn<C> {
+        Conn {
+            conn: Some(conn),
+            pool,
+            broken: false,
+        }
+    }
+
+    /// Marks the connection as broken, for when the caller knows it can't be reused (e.g. after a
+    /// protocol error). When this is dropped, the connection is closed instead of going back to
+    /// the pool, just like when `ManageConnection::has_broken` returns true.
+    pub fn mark_broken(&mut self) {
+        self.broken = true;
+    }
+
+    /// Takes the connection out of the pool for good, e.g. for long-lived work that needs to own
+    /// it outright. The pool stops counting it, so another connection can be made in its place.
+    pub fn detach(mut self) -> C::Connection {
+        let conn = self.conn.take().unwrap();
+        self.pool.detach();
+        conn.conn
+    }
 }
 
 impl<C: ManageConnection> Deref for Conn<C> {
@@ -81,18 +109,20 @@ impl<C: ManageConnection> DerefMut for Conn<C> {
 
 impl<C: ManageConnection> Drop for Conn<C> {
     fn drop(&mut self) {
-        let conn = self.conn.take().unwrap();
-        self.pool.put_back(conn);
+        // The connection is only missing if it has been detached
+        if let Some(conn) = self.conn.take() {
+            self.pool.return_conn(conn, self.broken);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tests::DummyManager;
+    use crate::tests::{count, run_paused, DummyManager, TestManager};
     use crate::Config;
-    use tokio::runtime::current_thread::Runtime;
-    use Pool;
+    use std::time::Duration;
+    use tokio::time::timeout;
 
     #[test]
     fn conn_pushes_back_into_pool_after_drop() {
@@ -100,15 +130,70 @@ mod tests {
         let config = Config {
             min_size: 2,
             max_size: 2,
+            ..Default::default()
         };
 
-        Runtime::new().expect("could not run").block_on(async {
+        run_paused(async {
             let pool = Pool::new(mngr, config).await.unwrap();
             assert_eq!(pool.idle_conns().await, 2);
             let conn = pool.connection().await.unwrap();
             assert_eq!(pool.idle_conns().await, 1);
             ::std::mem::drop(conn);
+            // put_back runs on a spawned task, give it a chance to store the connection
+            tokio::time::delay_for(Duration::from_millis(10)).await;
             assert_eq!(pool.idle_conns().await, 2);
         });
     }
+
+    #[test]
+    fn conn_marked_broken_is_closed_and_replaced() {
+        let mngr = TestManager {
+            invalid: true,
+            ..Default::default()
+        };
+        let config = Config {
+            min_size: 1,
+            max_size: 1,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let mut conn = pool.connection().await.unwrap();
+            conn.mark_broken();
+            ::std::mem::drop(conn);
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            assert_eq!(pool.total_conns().await, 1);
+            assert_eq!(pool.idle_conns().await, 1);
+            let connects = count(&pool.conn_pool.manager().connects);
+            assert_eq!(connects, 2);
+        });
+    }
+
+    #[test]
+    fn detached_conn_frees_up_its_slot() {
+        let mngr = TestManager {
+            invalid: true,
+            ..Default::default()
+        };
+        let config = Config {
+            min_size: 1,
+            max_size: 1,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let conn = pool.connection().await.unwrap();
+            let () = conn.detach();
+            let conn = timeout(Duration::from_millis(100), pool.connection())
+                .await
+                .expect("timed out")
+                .unwrap();
+            assert_eq!(pool.total_conns().await, 1);
+            let connects = count(&pool.conn_pool.manager().connects);
+            assert_eq!(connects, 2);
+            drop(conn);
+        });
+    }
 }
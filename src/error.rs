@@ -1,18 +1,86 @@
+/// Errors coming from the connection pool itself, rather than from the connection manager.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum InternalError {
-    Other(String),
+    /// Timed out waiting for a connection, or for the pool to close.
+    Timeout,
+    /// The pool has been closed, see `Pool::close`.
+    PoolClosed,
+    /// The pool stopped waiting on a connection for the caller without giving it one, e.g. because
+    /// the runtime is shutting down.
+    WaiterCancelled,
+    /// Too many calls are already waiting for a connection, see `Config::max_waiters`.
+    TooManyWaiters,
+    /// A connection couldn't be made for the caller. The pool makes connections on behalf of calls
+    /// that are waiting, so the manager's error went to the log rather than to the caller.
+    ConnectFailed,
+    /// There are no idle connections and the pool is at `Config::max_size`.
+    Exhausted,
+    /// The pool's `Config` is invalid.
+    InvalidConfig(ConfigError),
 }
 
 impl std::error::Error for InternalError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
+        match self {
+            InternalError::InvalidConfig(error) => Some(error),
+            _ => None,
+        }
     }
 }
 
 impl std::fmt::Display for InternalError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            InternalError::Other(string) => write!(f, "{}", string),
+            InternalError::Timeout => write!(f, "timed out waiting for a connection"),
+            InternalError::PoolClosed => write!(f, "the pool has been closed"),
+            InternalError::WaiterCancelled => {
+                write!(f, "stopped waiting for a connection before getting one")
+            }
+            InternalError::TooManyWaiters => {
+                write!(f, "too many callers are waiting for a connection")
+            }
+            InternalError::ConnectFailed => write!(f, "unable to make a new connection"),
+            InternalError::Exhausted => write!(f, "no connections are available"),
+            InternalError::InvalidConfig(error) => write!(f, "invalid config: {}", error),
+        }
+    }
+}
+
+/// Why a `Config` is invalid, see `Config::validate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// `max_size` is 0, so the pool could never hand out a connection.
+    ZeroMaxSize,
+    /// `min_size` is greater than `max_size`.
+    MinSizeAboveMaxSize {
+        /// The config's `min_size`
+        min_size: usize,
+        /// The config's `max_size`
+        max_size: usize,
+    },
+    /// `maintenance_interval` is 0.
+    ZeroMaintenanceInterval,
+    /// The config couldn't be parsed, e.g. by `Config::from_env`.
+    Parse(String),
+}
+
+impl std::error::Error for ConfigError {}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::ZeroMaxSize => write!(f, "max_size must be greater than 0"),
+            ConfigError::MinSizeAboveMaxSize { min_size, max_size } => write!(
+                f,
+                "min_size ({}) must be less than or equal to max_size ({})",
+                min_size, max_size
+            ),
+            ConfigError::ZeroMaintenanceInterval => {
+                write!(f, "maintenance_interval must be greater than 0")
+            }
+            ConfigError::Parse(message) => write!(f, "unable to parse config: {}", message),
         }
     }
 }
@@ -40,63 +40,704 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crossbeam::queue::SegQueue;
-
-use std::sync::Arc;
-use tokio::sync::oneshot;
-use tokio::sync::Mutex;
+use log::debug;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::time::Duration;
+use tokio::sync::{oneshot, watch};
+use tokio::time::Instant;
 
+use crate::error::{ConfigError, InternalError};
+use crate::maintenance;
 use crate::manage_connection::ManageConnection;
-use crate::queue::{Live, Queue};
-use crate::Config;
-use crate::Error;
+use crate::observer::{EvictionReason, PoolObserver};
+use crate::queue::{Counts, Idle, Live, Queue};
+use crate::{Config, Error, State};
+
+/// Either a connection, or the error from trying to make one.
+pub type LiveResult<C> =
+    Result<Live<<C as ManageConnection>::Connection>, Error<<C as ManageConnection>::Error>>;
+
+/// What waiters are sent: a connection, or the error from trying to make one for them.
+pub type HandOffResult<C> = Result<HandOff<C>, Error<<C as ManageConnection>::Error>>;
+
+/// The sending half of a caller that is waiting for a connection.
+type Waiter<C> = oneshot::Sender<HandOffResult<C>>;
+
+/// A connection that has been sent to a waiter. The waiter can give up (e.g. because
+/// `Config::connection_timeout` elapsed) after the connection is sent but before taking it, in
+/// which case dropping this hands the connection off again, instead of losing it while it's
+/// still counted.
+pub struct HandOff<C: ManageConnection> {
+    conn: Option<Live<C::Connection>>,
+    conn_pool: Arc<ConnectionPool<C>>,
+}
+
+impl<C: ManageConnection> HandOff<C> {
+    /// Takes the connection, which is then up to the caller.
+    pub fn into_inner(mut self) -> Live<C::Connection> {
+        self.conn.take().unwrap()
+    }
+}
+
+impl<C: ManageConnection> Drop for HandOff<C> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            debug!("hand_off: waiter gave up before taking its connection, handing it off again");
+            self.conn_pool.hand_off(conn);
+        }
+    }
+}
+
+/// What `Pool::connection` should do next, see `ConnectionPool::checkout`.
+pub enum Checkout<C: ManageConnection> {
+    /// An idle connection is ready to be handed out
+    Idle(Live<C::Connection>),
+    /// A slot has been reserved in the connection count for a new connection, which the caller
+    /// needs to make
+    Connect,
+    /// The caller has been added to the waiters, and will be sent a connection
+    Wait(oneshot::Receiver<HandOffResult<C>>),
+}
+
+/// What `ConnectionPool::checkout` falls back to when there isn't an idle connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fallback {
+    /// Make a new connection if the pool is under `max_size`, otherwise wait for one to be handed
+    /// over
+    Wait,
+    /// Make a new connection if the pool is under `max_size`, otherwise fail
+    Connect,
+    /// Fail straight away
+    Fail,
+}
 
 /// Inner connection pool. Handles creating and holding the connections, as well as keeping track of
 /// futures that are waiting on connections.
 pub struct ConnectionPool<C: ManageConnection + Send> {
     /// Queue of connections in the pool
-    pub conns: Mutex<Arc<Queue<C::Connection>>>,
+    pub conns: Queue<C::Connection>,
     /// Queue of oneshot's that are waiting to be given a new connection when the current pool is
     /// already saturated.
-    waiting: SegQueue<oneshot::Sender<Live<C::Connection>>>,
+    ///
+    /// Connections are only put in `conns` while holding this lock, so one can't be stored just as
+    /// a waiter is added (and then never handed to it). The lock is never held across an await, or
+    /// while sending to a waiter.
+    waiting: Mutex<VecDeque<Waiter<C>>>,
     /// Connection manager used to create new connections as needed
     manager: C,
-    /// Configuration for the pool
-    config: Config,
+    /// Configuration for the pool, which can be replaced with `reconfigure`. Its sizes, observer
+    /// and name aren't read from here, see the fields below.
+    config: RwLock<Config>,
+    /// `Config::observer` the pool was created with
+    observer: Option<Arc<dyn PoolObserver>>,
+    /// `Config::name` the pool was created with
+    name: Option<String>,
+    /// Whether the maintenance task has been started
+    maintenance: AtomicBool,
+    /// Whether a task is making connections to replenish the pool, see `maintenance::spawn_replenish`
+    replenishing: AtomicBool,
+    /// Whether the pool has been closed. A closed pool doesn't hand out or store connections.
+    closed: AtomicBool,
+    /// Set to `true` when the pool is closed, and dropped along with the pool. See `closed`.
+    closed_tx: watch::Sender<bool>,
+    /// Kept to hand out clones of, since the sender can't make new receivers
+    closed_rx: watch::Receiver<bool>,
+    /// Current `min_size`, which starts out as `Config::min_size` and can be changed with `resize`
+    min_size: AtomicUsize,
+    /// Current `max_size`, which starts out as `Config::max_size` and can be changed with `resize`
+    max_size: AtomicUsize,
 }
 
 impl<C: ManageConnection> ConnectionPool<C> {
     /// Creates a new connection pool
     pub fn new(conns: Queue<C::Connection>, manager: C, config: Config) -> ConnectionPool<C> {
+        let (closed_tx, closed_rx) = watch::channel(false);
         ConnectionPool {
-            conns: Mutex::new(Arc::new(conns)),
-            waiting: SegQueue::new(),
+            conns,
+            waiting: Mutex::new(VecDeque::new()),
             manager,
-            config,
+            closed: AtomicBool::new(false),
+            closed_tx,
+            closed_rx,
+            min_size: AtomicUsize::new(config.min_size),
+            max_size: AtomicUsize::new(config.max_size),
+            observer: config.observer.clone(),
+            name: config.name.clone(),
+            maintenance: AtomicBool::new(false),
+            replenishing: AtomicBool::new(false),
+            config: RwLock::new(config),
+        }
+    }
+
+    /// Calls `f` with the pool's observer, if it has one
+    pub fn observe(&self, f: impl FnOnce(&dyn PoolObserver)) {
+        if let Some(observer) = &self.observer {
+            f(&**observer);
+        }
+    }
+
+    fn config(&self) -> RwLockReadGuard<'_, Config> {
+        self.config
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Replaces the pool's config, e.g. with one from `Pool::with_config_source`, if it's valid.
+    /// The new sizes are applied like `resize` does. The observer and name can't be changed, so
+    /// the ones in `config` are ignored.
+    pub fn reconfigure(self: &Arc<Self>, config: Config) -> Result<(), ConfigError> {
+        config.validate()?;
+        self.resize(Some(config.min_size), Some(config.max_size))?;
+        *self
+            .config
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = config;
+        Ok(())
+    }
+
+    /// Marks the maintenance task as started. Returns false if it already was.
+    pub fn start_maintenance(&self) -> bool {
+        !self.maintenance.swap(true, Ordering::SeqCst)
+    }
+
+    /// Marks the replenishing task as started. Returns false if it already was.
+    pub fn start_replenish(&self) -> bool {
+        !self.replenishing.swap(true, Ordering::SeqCst)
+    }
+
+    /// Marks the replenishing task as stopped, so the next `maintenance::spawn_replenish` starts a
+    /// new one.
+    pub fn stop_replenish(&self) {
+        self.replenishing.store(false, Ordering::SeqCst);
+    }
+
+    /// Tells the observer that `conn` is being closed for `reason`
+    pub fn evicted(&self, conn: &Live<C::Connection>, reason: EvictionReason) {
+        self.observe(|observer| {
+            observer.on_evicted(reason);
+            observer.on_closed(conn.live_since.elapsed());
+        });
+    }
+
+    /// Tells the observer that `conn` is being closed because it's broken
+    pub fn broken(&self, conn: &Live<C::Connection>) {
+        self.observe(|observer| {
+            observer.on_broken();
+            observer.on_closed(conn.live_since.elapsed());
+        });
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Returns a future that resolves once the pool is closed or dropped. It doesn't keep the pool
+    /// alive.
+    pub fn closed(&self) -> impl Future<Output = ()> {
+        let mut closed_rx = self.closed_rx.clone();
+        async move {
+            while let Some(closed) = closed_rx.recv().await {
+                if closed {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Marks the pool as closed, fails every waiter and closes all idle connections. Connections
+    /// that are checked out are closed as they come back.
+    pub fn close(&self) {
+        let mut waiting = self.waiting();
+        self.closed.store(true, Ordering::SeqCst);
+        let _ = self.closed_tx.broadcast(true);
+        while let Some(tx) = waiting.pop_front() {
+            waiting.observe(Event::WaiterDequeued);
+            let _ = tx.send(Err(Error::Internal(InternalError::PoolClosed)));
+        }
+        let closed = self
+            .conns
+            .clear(|conn| waiting.evicted(&conn, EvictionReason::PoolClosed));
+        debug!(
+            "close: closed {} idle connections, connection count is now: {}",
+            closed,
+            self.conns.total()
+        );
+    }
+
+    /// A snapshot of the pool's connection counts, waiters and limits.
+    pub fn state(&self) -> State {
+        // Waiters only change while we hold this lock, and the counts while `counts` holds the
+        // queue's, so they're all read at the same time
+        let mut waiting = self.waiting();
+        let Counts {
+            total,
+            idle,
+            pending,
+        } = self.conns.counts();
+        State {
+            total,
+            idle,
+            in_use: total.saturating_sub(idle + pending),
+            pending,
+            waiters: self.retain_live_waiters(&mut waiting),
+            min_size: self.min_size(),
+            max_size: self.max_size(),
         }
     }
 
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
     pub fn max_size(&self) -> usize {
-        self.config.max_size
+        self.max_size.load(Ordering::SeqCst)
+    }
+
+    #[cfg(test)]
+    pub fn manager(&self) -> &C {
+        &self.manager
+    }
+
+    pub fn min_size(&self) -> usize {
+        self.min_size.load(Ordering::SeqCst)
+    }
+
+    /// Changes `min_size` and/or `max_size`, as long as they still make sense together. Idle
+    /// connections above the new `max_size` are closed straight away, and checked out ones as they
+    /// are returned. Connecting up to a raised limit is left to the caller.
+    pub fn resize(
+        self: &Arc<Self>,
+        min_size: Option<usize>,
+        max_size: Option<usize>,
+    ) -> Result<(), ConfigError> {
+        // Hold the lock so concurrent resizes are validated against each other
+        let mut waiting = self.waiting();
+        let min_size = min_size.unwrap_or_else(|| self.min_size());
+        let max_size = max_size.unwrap_or_else(|| self.max_size());
+        if max_size == 0 {
+            return Err(ConfigError::ZeroMaxSize);
+        }
+        if min_size > max_size {
+            return Err(ConfigError::MinSizeAboveMaxSize { min_size, max_size });
+        }
+
+        self.min_size.store(min_size, Ordering::SeqCst);
+        self.max_size.store(max_size, Ordering::SeqCst);
+        let mut retired = 0;
+        while self.conns.total() > max_size {
+            match self.get_idle_locked(&mut waiting) {
+                Some(conn) => {
+                    waiting.evicted(&conn, EvictionReason::PoolShrunk);
+                    self.conns.decrement();
+                    retired += 1;
+                }
+                None => break,
+            }
+        }
+        debug!(
+            "resize: min_size is now {}, max_size is now {}, closed {} idle connections",
+            min_size, max_size, retired
+        );
+
+        Ok(())
+    }
+
+    pub fn max_lifetime(&self) -> Option<Duration> {
+        self.config().max_lifetime
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.config().idle_timeout
     }
 
+    pub fn health_check_interval(&self) -> Option<Duration> {
+        self.config().health_check_interval
+    }
+
+    pub fn health_check_timeout(&self) -> Duration {
+        self.config().health_check_timeout
+    }
+
+    pub fn maintenance_interval(&self) -> Duration {
+        self.config().maintenance_interval
+    }
+
+    pub fn connection_timeout(&self) -> Option<Duration> {
+        self.config().connection_timeout
+    }
+
+    /// Wraps a newly made connection, giving it a lifetime based on the pool's config
+    pub fn new_live(&self, conn: C::Connection) -> Live<C::Connection> {
+        Live::with_lifetime(conn, self.config().connection_lifetime())
+    }
+
+    /// Whether an idle connection needs to be validated before it's handed out
+    pub fn needs_validation(&self, conn: &Live<C::Connection>) -> bool {
+        let config = self.config();
+        if !config.test_on_checkout {
+            return false;
+        }
+
+        match config.validation_interval {
+            Some(interval) => conn.last_known_good().elapsed() >= interval,
+            None => true,
+        }
+    }
+
+    pub async fn is_valid(&self, conn: &mut Live<C::Connection>) -> Result<(), Error<C::Error>> {
+        self.manager.is_valid(&mut conn.conn).await?;
+        conn.last_validated = Some(Instant::now());
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip(self),
+            fields(pool = self.name().unwrap_or(""), latency_ms = tracing::field::Empty)
+        )
+    )]
     pub async fn connect(&self) -> Result<C::Connection, Error<C::Error>> {
-        self.manager.connect().await
+        self.conns.add_pending();
+        // Decrement on drop, in case whoever is connecting gives up part way through
+        let _pending = PendingGuard(&self.conns);
+        let start = Instant::now();
+        let result = match self.manager.connect().await {
+            Ok(mut conn) => self.manager.after_connect(&mut conn).await.map(|()| conn),
+            Err(err) => Err(err),
+        };
+        record!("latency_ms", start.elapsed().as_millis() as u64);
+        match result {
+            Ok(_) => self.observe(|observer| observer.on_connect(start.elapsed())),
+            Err(_) => self.observe(|observer| observer.on_connect_failed(start.elapsed())),
+        }
+        result
+    }
+
+    pub async fn before_acquire(
+        &self,
+        conn: &mut Live<C::Connection>,
+    ) -> Result<(), Error<C::Error>> {
+        self.manager.before_acquire(&mut conn.conn).await
     }
 
-    /// Adds a "waiter" to the queue of waiting futures. When a new connection becomes available,
-    /// the oneshot will be called with a new connection
-    pub fn notify_of_connection(&self, tx: oneshot::Sender<Live<C::Connection>>) {
-        self.waiting.push(tx);
+    pub async fn recycle(&self, conn: &mut Live<C::Connection>) -> Result<(), Error<C::Error>> {
+        self.manager.recycle(&mut conn.conn).await
     }
 
-    pub fn try_waiting(
+    pub async fn after_release(
         &self,
-    ) -> Option<oneshot::Sender<Live<<C as ManageConnection>::Connection>>> {
-        self.waiting.try_pop()
+        conn: &mut Live<C::Connection>,
+    ) -> Result<(), Error<C::Error>> {
+        self.manager.after_release(&mut conn.conn).await
+    }
+
+    /// Locks the queue of waiters. Nothing that's done while holding it can panic, so the lock
+    /// can't be poisoned in a way that matters.
+    fn waiting(&self) -> Waiting<'_, C> {
+        let waiters = self
+            .waiting
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        Waiting {
+            waiters: Some(waiters),
+            conn_pool: self,
+            events: Vec::new(),
+        }
+    }
+
+    /// Takes an idle connection, closing any that have expired along the way (and replenishing
+    /// the pool in their place).
+    fn get_idle(self: &Arc<Self>) -> Option<Live<C::Connection>> {
+        let order = self.config().idle_order;
+        let mut expired = false;
+        let conn = self.conns.get(order, |conn| {
+            expired = true;
+            self.evicted(&conn, EvictionReason::MaxLifetime)
+        });
+        if expired {
+            maintenance::spawn_replenish(self);
+        }
+        conn
+    }
+
+    /// Like `get_idle`, for when the lock on waiters is held.
+    fn get_idle_locked(
+        self: &Arc<Self>,
+        waiting: &mut Waiting<'_, C>,
+    ) -> Option<Live<C::Connection>> {
+        let order = self.config().idle_order;
+        let mut expired = false;
+        let conn = self.conns.get(order, |conn| {
+            expired = true;
+            waiting.evicted(&conn, EvictionReason::MaxLifetime)
+        });
+        if expired {
+            maintenance::spawn_replenish(self);
+        }
+        conn
+    }
+
+    /// Decides how a caller of `Pool::connection` gets a connection: an idle one, a new one (the
+    /// slot for it is reserved here), or by waiting for one to be handed over. Making a new
+    /// connection and waiting are only options if `fallback` allows them, otherwise
+    /// `InternalError::Exhausted` is returned.
+    ///
+    /// None of this awaits, so the caller makes any new connection without holding a lock.
+    pub fn checkout(self: &Arc<Self>, fallback: Fallback) -> Result<Checkout<C>, Error<C::Error>> {
+        if self.is_closed() {
+            return Err(Error::Internal(InternalError::PoolClosed));
+        }
+        // Idle connections can be taken without the lock
+        if let Some(conn) = self.get_idle() {
+            return Ok(Checkout::Idle(conn));
+        }
+
+        let mut waiting = self.waiting();
+        if self.is_closed() {
+            return Err(Error::Internal(InternalError::PoolClosed));
+        }
+        // Look again now that nothing can be stored, in case a connection came back meanwhile
+        if let Some(conn) = self.get_idle_locked(&mut waiting) {
+            return Ok(Checkout::Idle(conn));
+        }
+        if fallback == Fallback::Fail {
+            return Err(Error::Internal(InternalError::Exhausted));
+        }
+        if self.conns.safe_increment(self.max_size()).is_some() {
+            return Ok(Checkout::Connect);
+        }
+        if fallback == Fallback::Connect {
+            return Err(Error::Internal(InternalError::Exhausted));
+        }
+        if let Some(max_waiters) = self.config().max_waiters {
+            if self.retain_live_waiters(&mut waiting) >= max_waiters {
+                return Err(Error::Internal(InternalError::TooManyWaiters));
+            }
+        }
+
+        let (tx, rx) = oneshot::channel();
+        waiting.observe(Event::WaiterEnqueued);
+        waiting.push_back(tx);
+        Ok(Checkout::Wait(rx))
+    }
+
+    #[cfg(test)]
+    pub fn try_waiting(&self) -> Option<Waiter<C>> {
+        let waiting = self.waiting().pop_front();
+        if waiting.is_some() {
+            self.observe(|observer| observer.on_waiter_dequeued());
+        }
+        waiting
+    }
+
+    /// Whether there are any waiters that are still waiting for a connection.
+    pub fn has_waiters(&self) -> bool {
+        self.remove_stale_waiters() > 0
+    }
+
+    /// Sends `result` to the oldest waiter that is still waiting. If there isn't one, `result` is
+    /// given back, along with the lock on waiters.
+    ///
+    /// The lock is released while sending. A waiter can give up just as it's sent a connection,
+    /// in which case the `HandOff` may be dropped inside `send`, and hand the connection off again
+    /// (which takes the lock).
+    fn notify_waiter<'a>(
+        &'a self,
+        mut waiting: Waiting<'a, C>,
+        mut result: HandOffResult<C>,
+    ) -> Option<(Waiting<'a, C>, HandOffResult<C>)> {
+        loop {
+            let tx = match waiting.pop_front() {
+                Some(tx) => tx,
+                None => return Some((waiting, result)),
+            };
+            waiting.observe(Event::WaiterDequeued);
+            drop(waiting);
+
+            debug!("notify_waiter: got a waiting connection, sending");
+            result = match tx.send(result) {
+                Ok(()) => return None,
+                Err(result) => {
+                    debug!("notify_waiter: unable to send to waiter");
+                    result
+                }
+            };
+            waiting = self.waiting();
+        }
+    }
+
+    /// Hands a connection to the oldest waiter that is still waiting, or stores it in `conns` if
+    /// there isn't one. If the pool has been closed, the connection is closed instead.
+    pub fn hand_off(self: &Arc<Self>, conn: Live<C::Connection>) {
+        self.hand_off_locked(self.waiting(), conn);
+    }
+
+    fn hand_off_locked(self: &Arc<Self>, waiting: Waiting<'_, C>, conn: Live<C::Connection>) {
+        // first attempt to send it to any waiting requests (a closed pool has none)
+        let conn = HandOff {
+            conn: Some(conn),
+            conn_pool: Arc::clone(self),
+        };
+        let (mut waiting, conn) = match self.notify_waiter(waiting, Ok(conn)) {
+            Some((waiting, Ok(conn))) => (waiting, conn.into_inner()),
+            _ => return,
+        };
+
+        if self.is_closed() {
+            debug!("hand_off: pool is closed, closing connection");
+            waiting.evicted(&conn, EvictionReason::PoolClosed);
+            self.conns.decrement();
+            return;
+        }
+
+        // If there are no waiting requests, store it back in the pool
+        debug!("hand_off: no waiting connection, storing");
+        self.conns.store(conn);
+    }
+
+    /// Puts a connection that passed a health check back. It goes to a waiter if there is one,
+    /// otherwise it's put back in `conns` without resetting how long it has been idle.
+    pub fn restore_idle(self: &Arc<Self>, idle: Idle<C::Connection>) {
+        let mut waiting = self.waiting();
+        if self.is_closed() || self.retain_live_waiters(&mut waiting) > 0 {
+            self.hand_off_locked(waiting, idle.conn);
+        } else {
+            self.conns.restore(idle);
+        }
+    }
+
+    /// Fails the oldest waiter that is still waiting with `InternalError::ConnectFailed`, because
+    /// the connection made on its behalf couldn't be established.
+    pub fn fail_waiter(&self) {
+        let err = Error::Internal(InternalError::ConnectFailed);
+        if self.notify_waiter(self.waiting(), Err(err)).is_some() {
+            debug!("fail_waiter: no waiter left to fail");
+        }
+    }
+
+    /// Closes idle connections that have outlived their lifetime. Returns the number of connections
+    /// closed.
+    pub fn close_expired(&self) -> usize {
+        let mut waiting = self.waiting();
+        self.conns
+            .close_expired(|conn| waiting.evicted(&conn, EvictionReason::MaxLifetime))
+    }
+
+    /// Closes idle connections that have been idle for longer than `idle_timeout`, as long as that
+    /// doesn't take the pool below `min_size`. Returns the number of connections closed.
+    pub fn reap(&self, idle_timeout: Duration) -> usize {
+        // Idle connections are taken out and put back, so hold the lock for waiters to see them
+        let mut waiting = self.waiting();
+        self.conns.reap(idle_timeout, self.min_size(), |conn| {
+            waiting.evicted(&conn, EvictionReason::IdleTimeout)
+        })
+    }
+
+    /// Takes the idle connections that are due for a health check, see
+    /// `Queue::take_for_health_check`.
+    pub fn take_for_health_check(&self, interval: Duration) -> Vec<Idle<C::Connection>> {
+        let _waiting = self.waiting();
+        self.conns.take_for_health_check(interval)
+    }
+
+    /// Removes waiters whose receiving end has been dropped, e.g. because the `Pool::connection`
+    /// call they belong to timed out. Live waiters keep their place in the queue.
+    /// Returns the number of waiters left.
+    pub fn remove_stale_waiters(&self) -> usize {
+        self.retain_live_waiters(&mut self.waiting())
+    }
+
+    fn retain_live_waiters(&self, waiting: &mut Waiting<'_, C>) -> usize {
+        let before = waiting.len();
+        waiting.retain(|tx| !tx.is_closed());
+        for _ in waiting.len()..before {
+            waiting.observe(Event::WaiterDequeued);
+        }
+
+        waiting.len()
     }
 
     pub fn has_broken(&self, conn: &mut Live<C::Connection>) -> bool {
         self.manager.has_broken(&mut conn.conn)
     }
+
+    pub fn timed_out(&self) -> Error<C::Error> {
+        self.manager.timed_out()
+    }
+}
+
+/// Decrements the count of pending connections when dropped.
+struct PendingGuard<'a, T: Send>(&'a Queue<T>);
+
+impl<'a, T: Send> Drop for PendingGuard<'a, T> {
+    fn drop(&mut self) {
+        self.0.remove_pending();
+    }
+}
+
+/// Something to tell the observer about once the lock on waiters has been released, see `Waiting`.
+enum Event {
+    WaiterEnqueued,
+    WaiterDequeued,
+    /// A connection was evicted for a reason, after being open for a while
+    Evicted(EvictionReason, Duration),
+}
+
+/// The locked queue of waiters, see `ConnectionPool::waiting`.
+///
+/// Observer callbacks for what's done while holding the lock are only made once it's released, so
+/// an observer can call back into the pool (e.g. `Pool::state`) without deadlocking.
+struct Waiting<'a, C: ManageConnection> {
+    waiters: Option<MutexGuard<'a, VecDeque<Waiter<C>>>>,
+    conn_pool: &'a ConnectionPool<C>,
+    events: Vec<Event>,
+}
+
+impl<'a, C: ManageConnection> Waiting<'a, C> {
+    /// Tells the observer about `event` once the lock is released
+    fn observe(&mut self, event: Event) {
+        if self.conn_pool.observer.is_some() {
+            self.events.push(event);
+        }
+    }
+
+    /// Like `ConnectionPool::evicted`, once the lock is released
+    fn evicted(&mut self, conn: &Live<C::Connection>, reason: EvictionReason) {
+        self.observe(Event::Evicted(reason, conn.live_since.elapsed()));
+    }
+}
+
+impl<'a, C: ManageConnection> Deref for Waiting<'a, C> {
+    type Target = VecDeque<Waiter<C>>;
+    fn deref(&self) -> &Self::Target {
+        self.waiters.as_ref().unwrap()
+    }
+}
+
+impl<'a, C: ManageConnection> DerefMut for Waiting<'a, C> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.waiters.as_mut().unwrap()
+    }
+}
+
+impl<'a, C: ManageConnection> Drop for Waiting<'a, C> {
+    fn drop(&mut self) {
+        drop(self.waiters.take());
+        for event in self.events.drain(..) {
+            self.conn_pool.observe(|observer| match event {
+                Event::WaiterEnqueued => observer.on_waiter_enqueued(),
+                Event::WaiterDequeued => observer.on_waiter_dequeued(),
+                Event::Evicted(reason, lifetime) => {
+                    observer.on_evicted(reason);
+                    observer.on_closed(lifetime);
+                }
+            });
+        }
+    }
 }
@@ -43,22 +43,42 @@
 //!
 //! Any connection type that implements the `ManageConnection` trait can be used with this libary.
 
+/// Records `value` in `field` of the current `tracing` span. Does nothing without the `tracing`
+/// feature.
+macro_rules! record {
+    ($field:expr, $value:expr) => {
+        #[cfg(feature = "tracing")]
+        {
+            tracing::Span::current().record($field, &$value);
+        }
+    };
+}
+
+mod config;
 mod conn;
 mod error;
 mod inner;
+mod maintenance;
 mod manage_connection;
+mod observer;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
 mod queue;
 
 use futures::stream::{self, StreamExt};
-use log::debug;
+use log::{debug, warn};
 use std::iter::Iterator;
 use std::sync::Arc;
-use tokio::sync::oneshot;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
 
+pub use config::{Config, ConfigBuilder, IdleOrder};
 pub use conn::{Conn, ConnFuture};
+pub use error::{ConfigError, InternalError};
 pub use manage_connection::ManageConnection;
+pub use observer::{EvictionReason, PoolObserver};
 
-use inner::ConnectionPool;
+use inner::{Checkout, ConnectionPool, Fallback, HandOff, LiveResult};
 use queue::{Live, Queue};
 
 /// General connection pool
@@ -66,13 +86,22 @@ pub struct Pool<C: ManageConnection + Send> {
     conn_pool: Arc<ConnectionPool<C>>,
 }
 
-/// Configuration for the connection pool
-#[derive(Debug)]
-pub struct Config {
-    /// Minimum number of connections in the pool. The pool will be initialied with this number of
-    /// connections
+/// A snapshot of the state of a pool, see `Pool::state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct State {
+    /// Total number of connections, including those that are still being established
+    pub total: usize,
+    /// Number of idle connections, ready to be handed out
+    pub idle: usize,
+    /// Number of connections that are checked out (or being checked by the pool)
+    pub in_use: usize,
+    /// Number of connections that are being established
+    pub pending: usize,
+    /// Number of calls to `Pool::connection` waiting for a connection
+    pub waiters: usize,
+    /// `Config::min_size` of the pool
     pub min_size: usize,
-    /// Max number of connections to keep in the pool
+    /// `Config::max_size` of the pool
     pub max_size: usize,
 }
 
@@ -80,11 +109,52 @@ pub struct Config {
 #[derive(Debug)]
 pub enum Error<E: Send + 'static> {
     /// Error coming from the connection pooling itself
-    Internal(error::InternalError),
+    Internal(InternalError),
     /// Error from the connection manager or the underlying client
     External(E),
 }
 
+impl<E: Send + 'static> Error<E> {
+    /// The pool's own error, if this is one.
+    pub fn internal(&self) -> Option<&InternalError> {
+        match self {
+            Error::Internal(error) => Some(error),
+            Error::External(_) => None,
+        }
+    }
+
+    /// Whether this is an `InternalError::Timeout`. Note that a manager can override
+    /// `ManageConnection::timed_out` to report connection timeouts with its own error instead.
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Error::Internal(InternalError::Timeout))
+    }
+
+    /// Whether this is an `InternalError::PoolClosed`.
+    pub fn is_pool_closed(&self) -> bool {
+        matches!(self, Error::Internal(InternalError::PoolClosed))
+    }
+
+    /// Whether this is an `InternalError::WaiterCancelled`.
+    pub fn is_waiter_cancelled(&self) -> bool {
+        matches!(self, Error::Internal(InternalError::WaiterCancelled))
+    }
+
+    /// Whether this is an `InternalError::TooManyWaiters`.
+    pub fn is_too_many_waiters(&self) -> bool {
+        matches!(self, Error::Internal(InternalError::TooManyWaiters))
+    }
+
+    /// Whether this is an `InternalError::ConnectFailed`.
+    pub fn is_connect_failed(&self) -> bool {
+        matches!(self, Error::Internal(InternalError::ConnectFailed))
+    }
+
+    /// Whether this is an `InternalError::Exhausted`.
+    pub fn is_exhausted(&self) -> bool {
+        matches!(self, Error::Internal(InternalError::Exhausted))
+    }
+}
+
 impl<E> std::error::Error for Error<E>
 where
     E: std::error::Error + 'static + Send + Sync,
@@ -109,15 +179,6 @@ where
     }
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Config {
-            max_size: 10,
-            min_size: 1,
-        }
-    }
-}
-
 /// Returns a new `Pool` referencing the same state as `self`.
 impl<C> Clone for Pool<C>
 where
@@ -134,137 +195,449 @@ impl<C: ManageConnection + Send> Pool<C> {
     /// Creates a new connection pool
     ///
     /// The returned future will resolve to the pool if successful, which can then be used
-    /// immediately.
+    /// immediately. An invalid `config` is rejected with `InternalError::InvalidConfig`.
     pub async fn new(manager: C, config: Config) -> Result<Pool<C>, Error<C::Error>> {
-        assert!(
-            config.max_size >= config.min_size,
-            "max_size of pool must be greater than or equal to the min_size"
-        );
+        config
+            .validate()
+            .map_err(|err| Error::Internal(InternalError::InvalidConfig(err)))?;
 
-        let conns: stream::futures_unordered::FuturesUnordered<_> = std::iter::repeat(&manager)
-            .take(config.min_size)
-            .map(|c| c.connect())
+        let conn_pool = Arc::new(ConnectionPool::new(Queue::new(), manager, config));
+        let conns: stream::futures_unordered::FuturesUnordered<_> = (0..conn_pool.min_size())
+            .map(|_| conn_pool.connect())
             .collect();
         let conns = conns.collect::<Vec<_>>().await;
         let conns: Result<Vec<_>, _> = conns.into_iter().collect();
-        let conns = conns?.into_iter().fold(Queue::new(), |conns, conn| {
-            conns.new_conn(Live::new(conn));
-            conns
-        });
+        for conn in conns? {
+            conn_pool.conns.new_conn(conn_pool.new_live(conn));
+        }
 
-        let conn_pool = Arc::new(ConnectionPool::new(conns, manager, config));
+        maintenance::spawn(&conn_pool);
         Ok(Pool { conn_pool })
     }
 
+    /// Creates a new connection pool with the latest config from `configs`, and keeps applying the
+    /// configs it receives after that.
+    ///
+    /// Changes to sizes are applied like `set_max_size` and `set_min_size` do, and changes to
+    /// timeouts and lifetimes take effect the next time they're used. A config's `observer` and
+    /// `name` can't be changed, so those are only read from the first one. A config that's invalid
+    /// (see `Config::validate`) is rejected: the pool keeps its current config, logs a warning and
+    /// tells the observer with `PoolObserver::on_config_rejected`.
+    ///
+    /// Configs stop being applied once the sending half of `configs` is dropped, or the pool is
+    /// dropped or closed.
+    pub async fn with_config_source(
+        manager: C,
+        mut configs: watch::Receiver<Config>,
+    ) -> Result<Pool<C>, Error<C::Error>> {
+        // The first `recv` resolves straight away with the current config
+        let config = match configs.recv().await {
+            Some(config) => config,
+            None => configs.borrow().clone(),
+        };
+        let pool = Pool::new(manager, config).await?;
+
+        let closed = pool.conn_pool.closed();
+        let conn_pool = Arc::downgrade(&pool.conn_pool);
+        tokio::spawn(async move {
+            tokio::pin!(closed);
+            loop {
+                // Don't wait on the config source for a pool that's gone
+                let config = tokio::select! {
+                    config = configs.recv() => match config {
+                        Some(config) => config,
+                        None => break,
+                    },
+                    () = &mut closed => {
+                        debug!("with_config_source: pool has been dropped or closed, stopping");
+                        return;
+                    }
+                };
+                let conn_pool = match conn_pool.upgrade() {
+                    Some(conn_pool) if !conn_pool.is_closed() => conn_pool,
+                    _ => return,
+                };
+                match conn_pool.reconfigure(config) {
+                    Ok(()) => {
+                        debug!("with_config_source: applied a new config");
+                        maintenance::spawn(&conn_pool);
+                        maintenance::spawn_replenish(&conn_pool);
+                    }
+                    Err(err) => {
+                        warn!("with_config_source: rejected a new config: {}", err);
+                        conn_pool.observe(|observer| observer.on_config_rejected(&err));
+                    }
+                }
+            }
+            debug!("with_config_source: config source has been dropped, stopping");
+        });
+
+        Ok(pool)
+    }
+
     /// Returns a future that resolves to a connection from the pool.
     ///
     /// If there are connections that are available to be used, the future will resolve immediately,
     /// otherwise, the connection will be in a pending state until a future is returned to the pool.
     ///
-    /// This **does not** implement any timeout functionality. Timeout functionality can be added
-    /// by calling `.timeout` on the returned future.
+    /// If `Config::connection_timeout` is set, the future will resolve to the manager's
+    /// `timed_out` error once the timeout has elapsed without a connection becoming available.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip(self),
+            fields(
+                pool = self.name().unwrap_or(""),
+                wait_ms = tracing::field::Empty,
+                new_connection = false,
+            )
+        )
+    )]
     pub async fn connection(&self) -> Result<Conn<C>, Error<C::Error>> {
-        let conns = self.conn_pool.conns.lock().await;
-        let conn = match conns.get() {
-            Some(conn) => {
-                debug!("connection: connection already in pool and ready to go");
-                Ok(conn)
-            }
-            None => {
-                debug!("connection: try spawn connection");
-                match Self::try_spawn_connection(&self, &conns).await {
-                    Some(result) => result,
-                    None => {
-                        let (tx, rx) = oneshot::channel();
-                        debug!("connection: pushing to notify of connection");
-                        self.conn_pool.notify_of_connection(tx);
-                        match rx.await {
-                            Ok(conn) => Ok(conn),
-                            Err(e) => Err(Error::Internal(error::InternalError::Other(format!(
-                                "rx error {}",
-                                e
-                            )))),
-                        }
+        let start = Instant::now();
+        let result = self.get_connection(Fallback::Wait).await;
+        record!("wait_ms", start.elapsed().as_millis() as u64);
+        if result.is_ok() {
+            self.conn_pool
+                .observe(|observer| observer.on_checkout(start.elapsed()));
+        }
+        result
+    }
+
+    /// Like `connection`, but never waits for a connection to be returned to the pool.
+    ///
+    /// Resolves to an idle connection if there is one, or a new connection if the pool is under
+    /// `max_size`. Otherwise, fails straight away with `InternalError::Exhausted`. Making the new
+    /// connection is bounded by `Config::connection_timeout`, like `connection` is.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip(self),
+            fields(
+                pool = self.name().unwrap_or(""),
+                wait_ms = tracing::field::Empty,
+                new_connection = false,
+            )
+        )
+    )]
+    pub async fn try_connection(&self) -> Result<Conn<C>, Error<C::Error>> {
+        let start = Instant::now();
+        let result = self.get_connection(Fallback::Connect).await;
+        record!("wait_ms", start.elapsed().as_millis() as u64);
+        if result.is_ok() {
+            self.conn_pool
+                .observe(|observer| observer.on_checkout(start.elapsed()));
+        }
+        result
+    }
+
+    /// Like `try_connection`, but never makes a new connection either.
+    ///
+    /// Resolves to an idle connection if there is one, and fails straight away with
+    /// `InternalError::Exhausted` otherwise.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip(self),
+            fields(pool = self.name().unwrap_or(""), wait_ms = tracing::field::Empty)
+        )
+    )]
+    pub async fn try_idle_connection(&self) -> Result<Conn<C>, Error<C::Error>> {
+        let start = Instant::now();
+        let result = self.get_connection(Fallback::Fail).await;
+        record!("wait_ms", start.elapsed().as_millis() as u64);
+        if result.is_ok() {
+            self.conn_pool
+                .observe(|observer| observer.on_checkout(start.elapsed()));
+        }
+        result
+    }
+
+    /// Gets a connection with `acquire`, giving up with the manager's `timed_out` error after
+    /// `Config::connection_timeout`.
+    async fn get_connection(&self, fallback: Fallback) -> Result<Conn<C>, Error<C::Error>> {
+        let timeout = match self.conn_pool.connection_timeout() {
+            Some(timeout) => timeout,
+            None => return self.acquire(fallback).await,
+        };
+
+        // Bind the result so the inner future (and the waiter's receiver) is dropped before we
+        // clean up the waiting queue.
+        let result = tokio::time::timeout(timeout, self.acquire(fallback)).await;
+        match result {
+            Ok(result) => result,
+            Err(_) => {
+                debug!("connection: timed out waiting for a connection");
+                self.conn_pool.remove_stale_waiters();
+                Err(self.conn_pool.timed_out())
+            }
+        }
+    }
+
+    /// Gets an idle connection, or falls back to making a new one or waiting for one to be handed
+    /// over if `fallback` allows it. Fails with `InternalError::Exhausted` otherwise.
+    async fn acquire(&self, fallback: Fallback) -> Result<Conn<C>, Error<C::Error>> {
+        loop {
+            let conn = self.get_live(fallback).await?;
+            if let Some(conn) = self.before_acquire(conn).await {
+                return Ok(Conn::new(conn, self.clone()));
+            }
+        }
+    }
+
+    /// Gets a connection for `acquire`, before `ManageConnection::before_acquire` has been called
+    /// on it.
+    async fn get_live(&self, fallback: Fallback) -> LiveResult<C> {
+        loop {
+            match self.conn_pool.checkout(fallback)? {
+                Checkout::Idle(conn) => {
+                    debug!("connection: connection already in pool and ready to go");
+                    if let Some(conn) = self.validate(conn).await {
+                        return Ok(conn);
                     }
                 }
+                Checkout::Connect => {
+                    debug!("connection: making a new connection");
+                    let result = self.spawn_connection().await;
+                    record!("new_connection", result.is_ok());
+                    return result;
+                }
+                Checkout::Wait(rx) => {
+                    debug!("connection: waiting to be handed a connection");
+                    return rx
+                        .await
+                        .unwrap_or_else(|_| Err(Error::Internal(InternalError::WaiterCancelled)))
+                        .map(HandOff::into_inner);
+                }
             }
-        }?;
-        Ok(Conn {
-            conn: Some(conn),
-            pool: self.clone(),
-        })
+        }
     }
-    /// Attempt to spawn a new connection. If we're not already over the max number of connections,
-    /// a future will be returned that resolves to the new connection.
-    /// Otherwise, None will be returned
-    pub(crate) async fn try_spawn_connection(
-        this: &Self,
-        conns: &Arc<queue::Queue<<C as ManageConnection>::Connection>>,
-    ) -> Option<Result<Live<C::Connection>, Error<C::Error>>> {
-        if conns.safe_increment(this.conn_pool.max_size()).is_some() {
-            let result = this.conn_pool.connect().await;
-            Some(match result {
-                Ok(conn) => Ok(Live::new(conn)),
-                Err(err) => {
-                    // if we weren't able to make a new connection, we need to decrement
-                    // connections, since we preincremented the connection count for this  one
-                    conns.decrement();
-                    Err(err)
-                }
-            })
-        } else {
-            None
+
+    /// Calls `ManageConnection::before_acquire` on a connection that's about to be handed out. If
+    /// that fails, the connection is closed and `None` is returned.
+    async fn before_acquire(&self, mut conn: Live<C::Connection>) -> Option<Live<C::Connection>> {
+        // Make sure the connection is uncounted if we end up closing it (or are dropped part way)
+        let guard = DecrementOnDrop(Some(&self.conn_pool));
+        match self.conn_pool.before_acquire(&mut conn).await {
+            Ok(()) => {
+                guard.disarm();
+                Some(conn)
+            }
+            Err(err) => {
+                debug!("connection: before_acquire failed, closing: {:?}", err);
+                self.conn_pool.broken(&conn);
+                None
+            }
+        }
+    }
+
+    /// Validates an idle connection with `ManageConnection::is_valid`, if `Config::test_on_checkout`
+    /// calls for it. A connection that fails validation is closed, and `None` is returned.
+    async fn validate(&self, mut conn: Live<C::Connection>) -> Option<Live<C::Connection>> {
+        if !self.conn_pool.needs_validation(&conn) {
+            return Some(conn);
+        }
+
+        // The connection is still counted while we validate it, so make sure it's uncounted if we
+        // end up closing it (or are dropped part way through)
+        let guard = DecrementOnDrop(Some(&self.conn_pool));
+        match self.conn_pool.is_valid(&mut conn).await {
+            Ok(()) => {
+                guard.disarm();
+                Some(conn)
+            }
+            Err(err) => {
+                debug!(
+                    "connection: connection failed validation, closing: {:?}",
+                    err
+                );
+                self.conn_pool
+                    .evicted(&conn, EvictionReason::FailedValidation);
+                None
+            }
         }
     }
+
+    /// Makes a new connection, for which `ConnectionPool::checkout` has already reserved a slot in
+    /// the connection count. No lock is held while connecting.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip(self),
+            fields(pool = self.name().unwrap_or(""))
+        )
+    )]
+    async fn spawn_connection(&self) -> LiveResult<C> {
+        // If we don't make it to a new connection (either because connecting failed or because
+        // the caller timed out and dropped us), we need to decrement connections, since we
+        // preincremented the connection count for this one. Others may have started waiting while
+        // we were connecting, and the guard connects for them in that case.
+        let guard = DecrementOnDrop(Some(&self.conn_pool));
+        let conn = self.conn_pool.connect().await?;
+        guard.disarm();
+        Ok(self.conn_pool.new_live(conn))
+    }
+
     /// Receive a connection back to be stored in the pool. This could have one
-    /// of two outcomes:
+    /// of these outcomes:
     /// * The connection will be passed to a waiting future, if any exist.
     /// * The connection will be put back into the connection pool.
-    pub fn put_back(&self, mut conn: Live<C::Connection>) {
+    /// * If the connection is broken (or fails `ManageConnection::recycle` or `after_release`) or
+    ///   expired, it will be closed. A new connection will be made in its place if there are
+    ///   waiters or the pool has dropped below `min_size`.
+    /// * If the pool has more connections than its `max_size` (see `set_max_size`), it will be
+    ///   closed.
+    pub fn put_back(&self, conn: Live<C::Connection>) {
+        self.return_conn(conn, false)
+    }
+
+    /// Like `put_back`, but closes the connection without asking the manager if it's broken when
+    /// `marked_broken` is set, see `Conn::mark_broken`.
+    pub(crate) fn return_conn(&self, mut conn: Live<C::Connection>, marked_broken: bool) {
         debug!("put_back: start put back");
         let conn_pool = Arc::clone(&self.conn_pool);
-        tokio::spawn(async move {
-            let broken = conn_pool.has_broken(&mut conn);
-            let conns = conn_pool.conns.lock().await;
-            debug!("put_back: got lock for put back");
-
-            if broken {
-                conns.decrement();
-                debug!("connection count is now: {:?}", conns.total());
-                //self.spawn_new_future_loop();
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "put_back",
+            pool = self.name().unwrap_or(""),
+            broken = tracing::field::Empty
+        );
+        let task = async move {
+            conn_pool.observe(|observer| observer.on_checkin());
+            let mut broken = marked_broken || conn_pool.has_broken(&mut conn);
+            if !broken && !conn.is_expired() {
+                if let Err(err) = conn_pool.recycle(&mut conn).await {
+                    debug!("put_back: recycle failed: {:?}", err);
+                    broken = true;
+                } else if let Err(err) = conn_pool.after_release(&mut conn).await {
+                    debug!("put_back: after_release failed: {:?}", err);
+                    broken = true;
+                }
+            }
+            record!("broken", broken);
+
+            if broken || conn.is_expired() {
+                debug!("put_back: connection is broken or expired, closing");
+                if broken {
+                    conn_pool.broken(&conn);
+                } else {
+                    conn_pool.evicted(&conn, EvictionReason::MaxLifetime);
+                }
+                conn_pool.conns.decrement();
+                debug!("connection count is now: {:?}", conn_pool.conns.total());
+                maintenance::spawn_replenish(&conn_pool);
                 return;
             }
 
-            // first attempt to send it to any waiting requests
-            let mut conn = conn;
-            while let Some(waiting) = conn_pool.try_waiting() {
-                debug!("put_back: got a waiting connection, sending");
-                conn = match waiting.send(conn) {
-                    Ok(_) => return,
-                    Err(conn) => {
-                        debug!("put_back: unable to send connection");
-                        conn
-                    }
-                };
+            if conn_pool
+                .conns
+                .safe_decrement(conn_pool.max_size())
+                .is_some()
+            {
+                debug!("put_back: pool is over max_size, closing");
+                conn_pool.evicted(&conn, EvictionReason::PoolShrunk);
+                return;
             }
-            debug!("put_back: no waiting connection, storing");
 
-            // If there are no waiting requests & we aren't over the max idle
-            // connections limit, attempt to store it back in the pool
-            conns.store(conn);
-        });
+            conn_pool.hand_off(conn);
+        };
+        #[cfg(feature = "tracing")]
+        let task = tracing::Instrument::instrument(task, span);
+        tokio::spawn(task);
+    }
+
+    /// Stops counting a connection that has been taken out of the pool with `Conn::detach`, and
+    /// makes a new one in its place if there are waiters or the pool has dropped below `min_size`.
+    pub(crate) fn detach(&self) {
+        self.conn_pool.conns.decrement();
+        debug!(
+            "detach: connection detached from the pool, connection count is now: {:?}",
+            self.conn_pool.conns.total()
+        );
+        maintenance::spawn_replenish(&self.conn_pool);
+    }
+
+    /// Closes the pool, resolving once every connection has been closed.
+    ///
+    /// New calls to `connection` fail with a "pool closed" error, and so does every call that is
+    /// currently waiting for a connection. Idle connections are closed immediately, and
+    /// connections that are checked out are closed when they are returned to the pool.
+    pub async fn close(&self) {
+        self.conn_pool.close();
+        self.conn_pool.conns.emptied().await;
+        debug!("close: all connections closed");
+    }
+
+    /// Like `close`, but gives up waiting for checked out connections to be returned after
+    /// `timeout`, returning a timeout error. The pool is still closed either way.
+    pub async fn close_timeout(&self, timeout: Duration) -> Result<(), Error<C::Error>> {
+        tokio::time::timeout(timeout, self.close())
+            .await
+            .map_err(|_| Error::Internal(InternalError::Timeout))
+    }
+
+    /// A snapshot of the pool's connection counts, waiters and limits, all taken at the same time.
+    pub async fn state(&self) -> State {
+        self.conn_pool.state()
+    }
+
+    /// Changes the most connections the pool will hold, which starts out as `Config::max_size`.
+    ///
+    /// When raised, new connections are made for any calls that are waiting for one. When
+    /// lowered, idle connections above the new limit are closed straight away, and checked out
+    /// ones as they are returned. Fails if `max_size` is 0 or below the current `min_size`.
+    pub fn set_max_size(&self, max_size: usize) -> Result<(), ConfigError> {
+        self.conn_pool.resize(None, Some(max_size))?;
+        maintenance::spawn_replenish(&self.conn_pool);
+        Ok(())
+    }
+
+    /// Changes the fewest connections the pool will hold, which starts out as `Config::min_size`.
+    ///
+    /// When raised, new connections are made up to the new limit in the background. Fails if
+    /// `min_size` is above the current `max_size`.
+    pub fn set_min_size(&self, min_size: usize) -> Result<(), ConfigError> {
+        self.conn_pool.resize(Some(min_size), None)?;
+        maintenance::spawn_replenish(&self.conn_pool);
+        Ok(())
+    }
+
+    /// The pool's `Config::name`, if it has one.
+    pub fn name(&self) -> Option<&str> {
+        self.conn_pool.name()
     }
 
     /// The total number of connections in the pool.
     pub async fn total_conns(&self) -> usize {
-        let conns = self.conn_pool.conns.lock().await;
-        conns.total()
+        self.conn_pool.conns.total()
     }
 
     /// The number of idle connections in the pool.
     pub async fn idle_conns(&self) -> usize {
-        let conns = self.conn_pool.conns.lock().await;
-        conns.idle()
+        self.conn_pool.conns.idle()
+    }
+}
+
+/// Decrements the connection count of a pool when dropped, unless disarmed first. Since that frees
+/// up capacity, the pool is also replenished, which makes a connection for any waiters.
+struct DecrementOnDrop<'a, C: ManageConnection>(Option<&'a Arc<ConnectionPool<C>>>);
+
+impl<'a, C: ManageConnection> DecrementOnDrop<'a, C> {
+    fn disarm(mut self) {
+        self.0.take();
+    }
+}
+
+impl<'a, C: ManageConnection> Drop for DecrementOnDrop<'a, C> {
+    fn drop(&mut self) {
+        if let Some(conn_pool) = self.0.take() {
+            conn_pool.conns.decrement();
+            maintenance::spawn_replenish(conn_pool);
+        }
     }
 }
 
@@ -274,9 +647,9 @@ mod tests {
     use async_trait::async_trait;
     use futures::future::FutureExt;
     use futures::join;
-    use std::time::Duration;
-    use tokio::future::FutureExt as TokioFutureExt;
-    use tokio::runtime::current_thread::Runtime;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use tokio::runtime::Runtime;
+    use tokio::time::timeout;
 
     #[derive(Debug)]
     pub struct DummyManager {}
@@ -289,20 +662,128 @@ mod tests {
             Ok(())
         }
 
-        async fn is_valid(&self, (): Self::Connection) -> Result<(), Error<Self::Error>> {
-            unimplemented!()
+        async fn is_valid(&self, _conn: &mut Self::Connection) -> Result<(), Error<Self::Error>> {
+            Ok(())
         }
 
         fn has_broken(&self, _conn: &mut Self::Connection) -> bool {
             false
         }
+    }
+
+    /// Manager for tests, which counts the calls made to it and can be set up to misbehave
+    #[derive(Debug, Default)]
+    pub struct TestManager {
+        /// How long connecting takes
+        pub connect_delay: Duration,
+        /// Number of upcoming connects that fail
+        pub connect_failures: AtomicUsize,
+        /// Whether connections are found to be broken when they're returned to the pool
+        pub broken: bool,
+        /// Whether connections always fail validation
+        pub invalid: bool,
+        /// Whether validating a connection never finishes
+        pub hang_validation: bool,
+        /// Whether the next `before_acquire` fails
+        pub fail_before_acquire: AtomicBool,
+        /// Whether the next `recycle` fails
+        pub fail_recycle: AtomicBool,
+        pub connects: AtomicUsize,
+        pub after_connects: AtomicUsize,
+        pub before_acquires: AtomicUsize,
+        pub recycles: AtomicUsize,
+        pub after_releases: AtomicUsize,
+        /// Number of validations that are running
+        pub validating: AtomicUsize,
+        /// Most validations that were running at once
+        pub max_validating: AtomicUsize,
+    }
+    #[async_trait]
+    impl ManageConnection for TestManager {
+        type Connection = ();
+        type Error = ();
+
+        async fn connect(&self) -> Result<Self::Connection, Error<Self::Error>> {
+            self.connects.fetch_add(1, Ordering::SeqCst);
+            tokio::time::delay_for(self.connect_delay).await;
+            let failures = &self.connect_failures;
+            if failures.load(Ordering::SeqCst) > 0 {
+                failures.fetch_sub(1, Ordering::SeqCst);
+                return Err(Error::External(()));
+            }
+            Ok(())
+        }
+
+        async fn is_valid(&self, _conn: &mut Self::Connection) -> Result<(), Error<Self::Error>> {
+            struct Validating<'a>(&'a AtomicUsize);
+            impl<'a> Drop for Validating<'a> {
+                fn drop(&mut self) {
+                    self.0.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+
+            let validating = self.validating.fetch_add(1, Ordering::SeqCst);
+            let _validating = Validating(&self.validating);
+            self.max_validating
+                .fetch_max(validating + 1, Ordering::SeqCst);
+            if self.hang_validation {
+                futures::future::pending::<()>().await;
+            }
+            if self.invalid {
+                return Err(Error::External(()));
+            }
+            Ok(())
+        }
+
+        fn has_broken(&self, _conn: &mut Self::Connection) -> bool {
+            self.broken
+        }
+
+        async fn after_connect(&self, _conn: &mut Self::Connection) -> Result<(), Error<()>> {
+            self.after_connects.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        async fn before_acquire(&self, _conn: &mut Self::Connection) -> Result<(), Error<()>> {
+            self.before_acquires.fetch_add(1, Ordering::SeqCst);
+            if self.fail_before_acquire.swap(false, Ordering::SeqCst) {
+                return Err(Error::External(()));
+            }
+            Ok(())
+        }
+
+        async fn recycle(&self, _conn: &mut Self::Connection) -> Result<(), Error<()>> {
+            self.recycles.fetch_add(1, Ordering::SeqCst);
+            if self.fail_recycle.swap(false, Ordering::SeqCst) {
+                return Err(Error::External(()));
+            }
+            Ok(())
+        }
 
-        /// Produce an error representing a connection timeout.
-        fn timed_out(&self) -> Error<Self::Error> {
-            unimplemented!()
+        async fn after_release(&self, _conn: &mut Self::Connection) -> Result<(), Error<()>> {
+            self.after_releases.fetch_add(1, Ordering::SeqCst);
+            Ok(())
         }
     }
 
+    pub fn count(counter: &AtomicUsize) -> usize {
+        counter.load(Ordering::SeqCst)
+    }
+
+    /// Runs `test` on a single threaded runtime with tokio's clock paused. Time only moves on when
+    /// every task is waiting on a timer, so sleeping in a test lets everything else run first.
+    pub fn run_paused<F: std::future::Future>(test: F) -> F::Output {
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .expect("could not run");
+        runtime.block_on(async {
+            tokio::time::pause();
+            test.await
+        })
+    }
+
     #[test]
     fn simple_pool_creation_and_connection() {
         let mngr = DummyManager {};
@@ -310,11 +791,7 @@ mod tests {
         Runtime::new().expect("could not run").block_on(async {
             let pool = Pool::new(mngr, config).await.unwrap();
             let conn = pool.connection().await.unwrap();
-            if let Some(Live {
-                conn: (),
-                live_since: _,
-            }) = conn.conn
-            {
+            if let Some(Live { conn: (), .. }) = conn.conn {
             } else {
                 panic!("connection is not correct type")
             }
@@ -327,12 +804,13 @@ mod tests {
         let config: Config = Config {
             max_size: 1,
             min_size: 1,
+            ..Default::default()
         };
 
         Runtime::new().expect("could not run").block_on(async {
             let pool = Pool::new(mngr, config).await.unwrap();
             ::std::mem::forget(pool.connection().await);
-            let result = pool.connection().timeout(Duration::from_millis(10)).await;
+            let result = timeout(Duration::from_millis(10), pool.connection()).await;
             match result {
                 Ok(_) => panic!("didn't timeout"),
                 Err(_err) => (),
@@ -346,31 +824,1077 @@ mod tests {
         let config: Config = Config {
             max_size: 2,
             min_size: 1,
+            ..Default::default()
         };
 
         // pool is of size 1, but is allowed to generate new connections up to 2.
         // When we try 2 connections, they should both pass without timing out
         Runtime::new().expect("could not run").block_on(async {
             let pool = Pool::new(mngr, config).await.unwrap();
-            let f1 = pool
-                .connection()
-                .timeout(Duration::from_millis(10))
-                .map(|res| match res {
-                    Ok(conn) => {
-                        ::std::mem::forget(conn);
-                    }
-                    Err(_) => panic!("second connection timed out"),
-                });
-            let f2 = pool
-                .connection()
-                .timeout(Duration::from_millis(10))
-                .map(|res| match res {
-                    Ok(conn) => {
-                        ::std::mem::forget(conn);
-                    }
-                    Err(_) => panic!("second connection timed out"),
-                });
+            let f1 = timeout(Duration::from_millis(10), pool.connection()).map(|res| match res {
+                Ok(conn) => {
+                    ::std::mem::forget(conn);
+                }
+                Err(_) => panic!("second connection timed out"),
+            });
+            let f2 = timeout(Duration::from_millis(10), pool.connection()).map(|res| match res {
+                Ok(conn) => {
+                    ::std::mem::forget(conn);
+                }
+                Err(_) => panic!("second connection timed out"),
+            });
             join!(f1, f2);
         });
     }
+
+    #[test]
+    fn it_times_out_with_connection_timeout() {
+        let mngr = DummyManager {};
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 1,
+            connection_timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let _conn = pool.connection().await.unwrap();
+            match pool.connection().await {
+                Err(err) if err.is_timeout() => (),
+                Err(err) => panic!("unexpected error {:?}", err),
+                Ok(_) => panic!("didn't timeout"),
+            }
+            assert!(pool.conn_pool.try_waiting().is_none());
+        });
+    }
+
+    #[test]
+    fn it_keeps_connections_sent_to_waiters_that_gave_up() {
+        let mngr = DummyManager {};
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 1,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let conn = pool.connection().await.unwrap();
+            let mut waiter = Box::pin(pool.connection());
+            assert!((&mut waiter).now_or_never().is_none());
+            // The connection is sent to the waiter, which gives up before taking it
+            drop(conn);
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            drop(waiter);
+            let state = pool.state().await;
+            assert_eq!((state.total, state.idle, state.in_use), (1, 1, 0));
+            timeout(Duration::from_millis(10), pool.connection())
+                .await
+                .expect("timed out")
+                .unwrap();
+        });
+    }
+
+    /// Waker that drops the future it wakes, like a `Pool::connection` that times out just as it's
+    /// handed a connection
+    struct DropOnWake<F>(std::sync::Mutex<Option<std::pin::Pin<Box<F>>>>);
+
+    impl<F: Send> futures::task::ArcWake for DropOnWake<F> {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.lock().unwrap().take();
+        }
+    }
+
+    #[test]
+    fn it_keeps_connections_sent_to_waiters_that_give_up_while_sending() {
+        let mngr = DummyManager {};
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 1,
+            ..Default::default()
+        };
+
+        // On another thread, so a deadlock doesn't hang the test
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            Runtime::new().expect("could not run").block_on(async {
+                let pool = Pool::new(mngr, config).await.unwrap();
+                let conn = pool.connection().await.unwrap();
+                let waiter = {
+                    let pool = pool.clone();
+                    async move { pool.connection().await }
+                };
+                let waiter = Arc::new(DropOnWake(std::sync::Mutex::new(Some(Box::pin(waiter)))));
+                let waker = futures::task::waker(Arc::clone(&waiter));
+                let mut cx = std::task::Context::from_waker(&waker);
+                let polled = {
+                    let mut waiting = waiter.0.lock().unwrap();
+                    std::future::Future::poll(waiting.as_mut().unwrap().as_mut(), &mut cx)
+                };
+                assert!(polled.is_pending());
+
+                // The waiter is dropped while the connection is being sent to it, which leaves
+                // the connection to be handed off again from inside the send
+                drop(conn);
+                while pool.state().await.idle == 0 {
+                    tokio::time::delay_for(Duration::from_millis(1)).await;
+                }
+                assert!(waiter.0.lock().unwrap().is_none());
+                tx.send(pool.state().await).unwrap();
+            });
+        });
+
+        let state = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("handing off the connection deadlocked");
+        assert_eq!((state.total, state.idle, state.waiters), (1, 1, 0));
+    }
+
+    #[test]
+    fn it_closes_connections_past_max_lifetime() {
+        let mngr = DummyManager {};
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 0,
+            max_lifetime: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+            let conn = pool.connection().await.unwrap();
+            assert_eq!(pool.total_conns().await, 1);
+            assert!(!conn.conn.as_ref().unwrap().is_expired());
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+            drop(conn);
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            assert_eq!(pool.total_conns().await, 0);
+            assert_eq!(pool.idle_conns().await, 0);
+        });
+    }
+
+    #[test]
+    fn it_replenishes_after_closing_expired_idle_connections() {
+        let mngr = TestManager::default();
+        let config: Config = Config {
+            max_size: 2,
+            min_size: 2,
+            max_lifetime: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+            // Both idle connections have expired, so a new one is made for this checkout
+            let _conn = pool.connection().await.unwrap();
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+            let state = pool.state().await;
+            assert_eq!((state.total, state.idle, state.in_use), (2, 1, 1));
+            assert_eq!(count(&pool.conn_pool.manager().connects), 4);
+        });
+    }
+
+    #[test]
+    fn it_closes_expired_idle_connections_in_the_background() {
+        let mngr = TestManager::default();
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 1,
+            max_lifetime: Some(Duration::from_millis(50)),
+            maintenance_interval: Duration::from_millis(10),
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            tokio::time::delay_for(Duration::from_millis(75)).await;
+            // Without ever being checked out, the connection was replaced once it expired
+            assert_eq!(count(&pool.conn_pool.manager().connects), 2);
+            assert_eq!(pool.total_conns().await, 1);
+            assert_eq!(pool.idle_conns().await, 1);
+        });
+    }
+
+    #[test]
+    fn it_closes_idle_connections_down_to_min_size() {
+        let mngr = DummyManager {};
+        let config: Config = Config {
+            max_size: 3,
+            min_size: 1,
+            idle_timeout: Some(Duration::from_millis(10)),
+            maintenance_interval: Duration::from_millis(10),
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let conns = vec![
+                pool.connection().await.unwrap(),
+                pool.connection().await.unwrap(),
+                pool.connection().await.unwrap(),
+            ];
+            assert_eq!(pool.total_conns().await, 3);
+            drop(conns);
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            assert_eq!(pool.total_conns().await, 1);
+            assert_eq!(pool.idle_conns().await, 1);
+        });
+    }
+
+    #[test]
+    fn it_replenishes_to_min_size_after_a_broken_connection() {
+        let mngr = TestManager {
+            broken: true,
+            ..Default::default()
+        };
+        let config: Config = Config {
+            max_size: 2,
+            min_size: 2,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            drop(pool.connection().await.unwrap());
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+            assert_eq!(pool.total_conns().await, 2);
+            assert_eq!(pool.idle_conns().await, 2);
+            let connects = count(&pool.conn_pool.manager().connects);
+            assert_eq!(connects, 3);
+        });
+    }
+
+    #[test]
+    fn it_connects_for_waiters_when_a_broken_connection_frees_capacity() {
+        let mngr = TestManager {
+            broken: true,
+            ..Default::default()
+        };
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 0,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let conn = pool.connection().await.unwrap();
+            let waiter = timeout(Duration::from_millis(100), pool.connection());
+            let release = async {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+                drop(conn);
+            };
+            let (result, ()) = join!(waiter, release);
+            match result {
+                Ok(Ok(_conn)) => (),
+                Ok(Err(err)) => panic!("unexpected error {:?}", err),
+                Err(_) => panic!("waiter was never given a connection"),
+            }
+        });
+    }
+
+    #[test]
+    fn it_runs_a_single_replenish_task() {
+        let mngr = TestManager {
+            connect_failures: AtomicUsize::new(usize::MAX),
+            ..Default::default()
+        };
+        let config: Config = Config {
+            max_size: 2,
+            min_size: 0,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            // Every call asks for replenishing, but only one task retries (and backs off)
+            for _ in 0..5 {
+                pool.set_min_size(2).unwrap();
+            }
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            let connects = count(&pool.conn_pool.manager().connects);
+            assert_eq!(connects, 1);
+
+            // Once it's back up to min_size the task stops, and the next call starts a new one
+            pool.conn_pool
+                .manager()
+                .connect_failures
+                .store(0, Ordering::SeqCst);
+            tokio::time::delay_for(Duration::from_millis(150)).await;
+            assert_eq!(pool.total_conns().await, 2);
+            pool.connection().await.unwrap().detach();
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            assert_eq!(pool.total_conns().await, 2);
+        });
+    }
+
+    #[test]
+    fn it_fails_waiters_when_connecting_for_them_fails() {
+        let mngr = TestManager::default();
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 0,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let mut conn = pool.connection().await.unwrap();
+            let waiter = timeout(Duration::from_millis(100), pool.connection());
+            let release = async {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+                pool.conn_pool
+                    .manager()
+                    .connect_failures
+                    .store(1, Ordering::SeqCst);
+                conn.mark_broken();
+                drop(conn);
+            };
+            let (result, ()) = join!(waiter, release);
+            match result {
+                Ok(Err(err)) if err.is_connect_failed() => (),
+                Ok(Err(err)) => panic!("unexpected error {:?}", err),
+                Ok(Ok(_)) => panic!("got a connection from a failed connect"),
+                Err(_) => panic!("waiter was never failed"),
+            }
+        });
+    }
+
+    #[test]
+    fn it_replaces_idle_connections_that_fail_validation_on_checkout() {
+        let mngr = TestManager {
+            invalid: true,
+            ..Default::default()
+        };
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 1,
+            test_on_checkout: true,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let _conn = timeout(Duration::from_millis(100), pool.connection())
+                .await
+                .expect("timed out")
+                .unwrap();
+            assert_eq!(pool.total_conns().await, 1);
+            let connects = count(&pool.conn_pool.manager().connects);
+            assert_eq!(connects, 2);
+        });
+    }
+
+    #[test]
+    fn it_skips_validation_for_recently_used_connections() {
+        let mngr = TestManager {
+            invalid: true,
+            ..Default::default()
+        };
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 1,
+            test_on_checkout: true,
+            validation_interval: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let _conn = pool.connection().await.unwrap();
+            let connects = count(&pool.conn_pool.manager().connects);
+            assert_eq!(connects, 1);
+        });
+    }
+
+    #[test]
+    fn it_replaces_idle_connections_that_fail_health_checks() {
+        let mngr = TestManager {
+            invalid: true,
+            ..Default::default()
+        };
+        let config: Config = Config {
+            max_size: 2,
+            min_size: 2,
+            health_check_interval: Some(Duration::from_millis(10)),
+            maintenance_interval: Duration::from_millis(10),
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            // Every connection fails its health check, so they keep being closed and replaced
+            assert!(pool.total_conns().await <= 2);
+            let connects = count(&pool.conn_pool.manager().connects);
+            assert!(connects > 2);
+        });
+    }
+
+    #[test]
+    fn it_times_out_health_checks() {
+        let mngr = TestManager {
+            hang_validation: true,
+            ..Default::default()
+        };
+        let config: Config = Config {
+            max_size: 2,
+            min_size: 2,
+            health_check_interval: Some(Duration::from_millis(10)),
+            health_check_timeout: Duration::from_millis(20),
+            maintenance_interval: Duration::from_millis(10),
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+            // Both connections are checked at once, and closed and replaced when that times out
+            let mngr = pool.conn_pool.manager();
+            assert_eq!(count(&mngr.max_validating), 2);
+            assert!(count(&mngr.connects) > 2);
+            assert!(pool.total_conns().await <= 2);
+        });
+    }
+
+    #[test]
+    fn it_closes_the_pool() {
+        let mngr = DummyManager {};
+        let config: Config = Config {
+            max_size: 2,
+            min_size: 2,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let conn1 = pool.connection().await.unwrap();
+            let conn2 = pool.connection().await.unwrap();
+            let waiter = pool.connection();
+            let close = async {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+                let closing = pool.close();
+                let release = async {
+                    tokio::time::delay_for(Duration::from_millis(10)).await;
+                    drop(conn1);
+                    drop(conn2);
+                };
+                join!(closing, release);
+            };
+            let (result, ()) = join!(waiter, close);
+            match result {
+                Err(Error::Internal(InternalError::PoolClosed)) => (),
+                Err(err) => panic!("unexpected error {:?}", err),
+                Ok(_) => panic!("got a connection from a closed pool"),
+            }
+            assert_eq!(pool.total_conns().await, 0);
+            match pool.connection().await {
+                Err(err) if err.is_pool_closed() => (),
+                _ => panic!("closed pool didn't reject connection"),
+            }
+        });
+    }
+
+    #[test]
+    fn it_times_out_closing_with_connections_checked_out() {
+        let mngr = DummyManager {};
+        let config: Config = Default::default();
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let _conn = pool.connection().await.unwrap();
+            match pool.close_timeout(Duration::from_millis(20)).await {
+                Err(Error::Internal(InternalError::Timeout)) => (),
+                _ => panic!("close didn't time out"),
+            }
+            assert_eq!(pool.total_conns().await, 1);
+            assert_eq!(pool.idle_conns().await, 0);
+        });
+    }
+
+    #[test]
+    fn it_reports_state() {
+        let mngr = DummyManager {};
+        let config: Config = Config {
+            max_size: 2,
+            min_size: 2,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let _conn1 = pool.connection().await.unwrap();
+            let _conn2 = pool.connection().await.unwrap();
+            let waiter = timeout(Duration::from_millis(50), pool.connection());
+            let check = async {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+                pool.state().await
+            };
+            let (_, state) = join!(waiter, check);
+            assert_eq!(
+                state,
+                State {
+                    total: 2,
+                    idle: 0,
+                    in_use: 2,
+                    pending: 0,
+                    waiters: 1,
+                    min_size: 2,
+                    max_size: 2,
+                }
+            );
+        });
+    }
+
+    #[derive(Debug, Default)]
+    struct CountingObserver {
+        connects: AtomicUsize,
+        checkouts: AtomicUsize,
+        checkins: AtomicUsize,
+        enqueued: AtomicUsize,
+        dequeued: AtomicUsize,
+        rejected: AtomicUsize,
+    }
+
+    impl PoolObserver for CountingObserver {
+        fn on_connect(&self, _latency: Duration) {
+            self.connects.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_checkout(&self, _wait: Duration) {
+            self.checkouts.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_checkin(&self) {
+            self.checkins.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_waiter_enqueued(&self) {
+            self.enqueued.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_waiter_dequeued(&self) {
+            self.dequeued.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_config_rejected(&self, _error: &ConfigError) {
+            self.rejected.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn it_notifies_the_observer() {
+        let mngr = DummyManager {};
+        let observer = Arc::new(CountingObserver::default());
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 1,
+            observer: Some(observer.clone()),
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let conn = pool.connection().await.unwrap();
+            let waiter = pool.connection();
+            let release = async {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+                drop(conn);
+            };
+            let (conn, ()) = join!(waiter, release);
+            drop(conn);
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        });
+        assert_eq!(count(&observer.connects), 1);
+        assert_eq!(count(&observer.checkouts), 2);
+        assert_eq!(count(&observer.checkins), 2);
+        assert_eq!(count(&observer.enqueued), 1);
+        assert_eq!(count(&observer.dequeued), 1);
+    }
+
+    /// Observer that calls `Pool::state` from every waiter and eviction callback, and counts the
+    /// calls that were blocked because the pool was holding a lock while calling it
+    #[derive(Default)]
+    struct StateObserver {
+        pool: std::sync::Mutex<Option<Pool<DummyManager>>>,
+        calls: AtomicUsize,
+        blocked: AtomicUsize,
+    }
+
+    impl StateObserver {
+        fn read_state(&self) {
+            let pool = match self.pool.lock().unwrap().clone() {
+                Some(pool) => pool,
+                None => return,
+            };
+            // On another thread, so a deadlock doesn't hang the test
+            let (tx, rx) = std::sync::mpsc::channel();
+            std::thread::spawn(move || tx.send(pool.conn_pool.state()));
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if rx.recv_timeout(Duration::from_millis(100)).is_err() {
+                self.blocked.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    impl std::fmt::Debug for StateObserver {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.debug_struct("StateObserver").finish()
+        }
+    }
+
+    impl PoolObserver for StateObserver {
+        fn on_waiter_enqueued(&self) {
+            self.read_state();
+        }
+
+        fn on_waiter_dequeued(&self) {
+            self.read_state();
+        }
+
+        fn on_evicted(&self, _reason: EvictionReason) {
+            self.read_state();
+        }
+    }
+
+    #[test]
+    fn it_notifies_the_observer_without_holding_locks() {
+        let mngr = DummyManager {};
+        let observer = Arc::new(StateObserver::default());
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 1,
+            observer: Some(observer.clone()),
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            *observer.pool.lock().unwrap() = Some(pool.clone());
+            let conn = pool.connection().await.unwrap();
+            let waiter = pool.connection();
+            let release = async {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+                drop(conn);
+            };
+            let (conn, ()) = join!(waiter, release);
+            drop(conn);
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            pool.close().await;
+            observer.pool.lock().unwrap().take();
+        });
+        // Enqueued and dequeued once, and the idle connection evicted on close
+        assert_eq!(count(&observer.calls), 3);
+        assert_eq!(count(&observer.blocked), 0);
+    }
+
+    #[test]
+    fn it_rejects_waiters_past_max_waiters() {
+        let mngr = DummyManager {};
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 1,
+            max_waiters: Some(1),
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let _conn = pool.connection().await.unwrap();
+            let waiter = timeout(Duration::from_millis(50), pool.connection());
+            let rejected = async {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+                pool.connection().await
+            };
+            let (waited, rejected) = join!(waiter, rejected);
+            assert!(waited.is_err(), "waiter was given a connection");
+            match rejected {
+                Err(err) if err.is_too_many_waiters() => (),
+                Err(err) => panic!("unexpected error {:?}", err),
+                Ok(_) => panic!("got a connection past max_waiters"),
+            }
+        });
+    }
+
+    #[test]
+    fn try_connection_fails_fast_when_exhausted() {
+        let mngr = DummyManager {};
+        let config: Config = Config {
+            max_size: 2,
+            min_size: 1,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            // One idle connection, then a new one since the pool is under max_size
+            let _conn1 = pool.try_connection().await.unwrap();
+            let _conn2 = pool.try_connection().await.unwrap();
+            match pool.try_connection().await {
+                Err(err) if err.is_exhausted() => (),
+                Err(err) => panic!("unexpected error {:?}", err),
+                Ok(_) => panic!("got a connection past max_size"),
+            }
+            assert_eq!(pool.state().await.waiters, 0);
+            assert!(pool.conn_pool.try_waiting().is_none());
+        });
+    }
+
+    #[test]
+    fn try_connection_times_out_making_a_new_connection() {
+        let mngr = TestManager {
+            connect_delay: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 0,
+            connection_timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let result = timeout(Duration::from_millis(100), pool.try_connection())
+                .await
+                .expect("connection_timeout wasn't applied");
+            match result {
+                Err(err) if err.is_timeout() => (),
+                Err(err) => panic!("unexpected error {:?}", err),
+                Ok(_) => panic!("slow connection didn't time out"),
+            }
+            assert_eq!(pool.total_conns().await, 0);
+        });
+    }
+
+    #[test]
+    fn try_idle_connection_never_connects() {
+        let mngr = TestManager {
+            invalid: true,
+            ..Default::default()
+        };
+        let config: Config = Config {
+            max_size: 2,
+            min_size: 1,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let _conn = pool.try_idle_connection().await.unwrap();
+            match pool.try_idle_connection().await {
+                Err(err) if err.is_exhausted() => (),
+                Err(err) => panic!("unexpected error {:?}", err),
+                Ok(_) => panic!("got a connection with none idle"),
+            }
+            assert_eq!(pool.total_conns().await, 1);
+            let connects = count(&pool.conn_pool.manager().connects);
+            assert_eq!(connects, 1);
+        });
+    }
+
+    #[test]
+    fn it_runs_the_lifecycle_hooks() {
+        let mngr = TestManager::default();
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 1,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            drop(pool.connection().await.unwrap());
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            let mngr = pool.conn_pool.manager();
+            assert_eq!(count(&mngr.after_connects), 1);
+            assert_eq!(count(&mngr.before_acquires), 1);
+            assert_eq!(count(&mngr.recycles), 1);
+            assert_eq!(count(&mngr.after_releases), 1);
+            assert_eq!(pool.idle_conns().await, 1);
+        });
+    }
+
+    #[test]
+    fn it_replaces_connections_that_fail_before_acquire() {
+        let mngr = TestManager {
+            fail_before_acquire: AtomicBool::new(true),
+            ..Default::default()
+        };
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 1,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let _conn = timeout(Duration::from_millis(100), pool.connection())
+                .await
+                .expect("timed out")
+                .unwrap();
+            assert_eq!(pool.total_conns().await, 1);
+            let mngr = pool.conn_pool.manager();
+            assert_eq!(count(&mngr.connects), 2);
+            assert_eq!(count(&mngr.before_acquires), 2);
+        });
+    }
+
+    #[test]
+    fn it_closes_connections_that_fail_to_recycle() {
+        let mngr = TestManager {
+            fail_recycle: AtomicBool::new(true),
+            ..Default::default()
+        };
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 1,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            drop(pool.connection().await.unwrap());
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            assert_eq!(pool.total_conns().await, 1);
+            assert_eq!(pool.idle_conns().await, 1);
+            let mngr = pool.conn_pool.manager();
+            assert_eq!(count(&mngr.connects), 2);
+            assert_eq!(count(&mngr.recycles), 1);
+            assert_eq!(count(&mngr.after_releases), 0);
+        });
+    }
+
+    #[test]
+    fn it_hands_out_connections_while_another_is_being_made() {
+        let mngr = TestManager {
+            connect_delay: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let config: Config = Config {
+            max_size: 2,
+            min_size: 1,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let conn = pool.connection().await.unwrap();
+            // The pool is under max_size, so this makes a new (slow) connection
+            let connecting = pool.connection();
+            let reuse = async {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+                drop(conn);
+                timeout(Duration::from_millis(50), pool.connection())
+                    .await
+                    .expect("checkout was blocked by a connection being made")
+                    .unwrap()
+            };
+            let (connecting, _reused) = join!(connecting, reuse);
+            connecting.unwrap();
+        });
+    }
+
+    #[test]
+    fn it_connects_for_waiters_when_a_connect_fails() {
+        let mngr = TestManager {
+            connect_failures: AtomicUsize::new(1),
+            connect_delay: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 0,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            // The first caller makes the (failing) connection, the second waits behind it
+            let connecting = pool.connection();
+            let waiter = async {
+                tokio::time::delay_for(Duration::from_millis(5)).await;
+                timeout(Duration::from_millis(100), pool.connection()).await
+            };
+            let (connecting, waited) = join!(connecting, waiter);
+            assert!(connecting.is_err(), "failing connect gave a connection");
+            match waited {
+                Ok(Ok(_conn)) => (),
+                Ok(Err(err)) => panic!("unexpected error {:?}", err),
+                Err(_) => panic!("waiter was never given a connection"),
+            }
+        });
+    }
+
+    #[test]
+    fn it_rejects_invalid_configs() {
+        let mngr = DummyManager {};
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 2,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            match Pool::new(mngr, config).await {
+                Err(Error::Internal(InternalError::InvalidConfig(
+                    ConfigError::MinSizeAboveMaxSize { .. },
+                ))) => (),
+                Err(err) => panic!("unexpected error {:?}", err),
+                Ok(_) => panic!("created a pool with an invalid config"),
+            }
+        });
+    }
+
+    #[test]
+    fn it_connects_for_waiters_when_max_size_is_raised() {
+        let mngr = DummyManager {};
+        let config: Config = Config {
+            max_size: 1,
+            min_size: 1,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let _conn = pool.connection().await.unwrap();
+            let waiter = timeout(Duration::from_millis(100), pool.connection());
+            let resize = async {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+                pool.set_max_size(2).unwrap();
+            };
+            let (result, ()) = join!(waiter, resize);
+            match result {
+                Ok(Ok(_conn)) => (),
+                Ok(Err(err)) => panic!("unexpected error {:?}", err),
+                Err(_) => panic!("waiter was never given a connection"),
+            }
+            assert_eq!(pool.state().await.max_size, 2);
+        });
+    }
+
+    #[test]
+    fn it_closes_surplus_connections_when_max_size_is_lowered() {
+        let mngr = DummyManager {};
+        let config: Config = Config {
+            max_size: 3,
+            min_size: 1,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            let conn1 = pool.connection().await.unwrap();
+            let conn2 = pool.connection().await.unwrap();
+            drop(pool.connection().await.unwrap());
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            assert_eq!(pool.total_conns().await, 3);
+
+            // The idle connection is closed straight away, the others as they come back
+            pool.set_max_size(1).unwrap();
+            assert_eq!(pool.total_conns().await, 2);
+            assert_eq!(pool.idle_conns().await, 0);
+            drop(conn1);
+            drop(conn2);
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            assert_eq!(pool.total_conns().await, 1);
+            assert_eq!(pool.idle_conns().await, 1);
+        });
+    }
+
+    #[test]
+    fn it_replenishes_when_min_size_is_raised() {
+        let mngr = DummyManager {};
+        let config: Config = Config {
+            max_size: 3,
+            min_size: 1,
+            ..Default::default()
+        };
+
+        run_paused(async {
+            let pool = Pool::new(mngr, config).await.unwrap();
+            match pool.set_min_size(4) {
+                Err(ConfigError::MinSizeAboveMaxSize { .. }) => (),
+                other => panic!("unexpected result {:?}", other),
+            }
+            match pool.set_max_size(0) {
+                Err(ConfigError::ZeroMaxSize) => (),
+                other => panic!("unexpected result {:?}", other),
+            }
+            pool.set_min_size(3).unwrap();
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            assert_eq!(pool.total_conns().await, 3);
+            assert_eq!(pool.idle_conns().await, 3);
+        });
+    }
+
+    #[test]
+    fn it_applies_configs_from_a_config_source() {
+        let mngr = DummyManager {};
+        let observer = Arc::new(CountingObserver::default());
+        let (tx, rx) = watch::channel(Config {
+            max_size: 1,
+            min_size: 1,
+            observer: Some(observer.clone()),
+            ..Default::default()
+        });
+
+        run_paused(async {
+            let pool = Pool::with_config_source(mngr, rx).await.unwrap();
+            tx.broadcast(Config {
+                max_size: 2,
+                min_size: 2,
+                connection_timeout: Some(Duration::from_millis(10)),
+                ..Default::default()
+            })
+            .unwrap();
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            let state = pool.state().await;
+            assert_eq!((state.min_size, state.max_size), (2, 2));
+            assert_eq!(state.total, 2);
+
+            let _conns = (pool.connection().await, pool.connection().await);
+            match pool.connection().await {
+                Err(err) if err.is_timeout() => (),
+                Err(err) => panic!("unexpected error {:?}", err),
+                Ok(_) => panic!("got a connection past max_size"),
+            }
+
+            // An invalid config is rejected, and the pool keeps the current one
+            tx.broadcast(Config {
+                max_size: 1,
+                min_size: 3,
+                ..Default::default()
+            })
+            .unwrap();
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            let state = pool.state().await;
+            assert_eq!((state.min_size, state.max_size), (2, 2));
+        });
+
+        assert_eq!(count(&observer.rejected), 1);
+    }
+
+    #[test]
+    fn it_stops_applying_configs_once_the_pool_is_closed_or_dropped() {
+        run_paused(async {
+            let (mut tx, rx) = watch::channel(Config::default());
+            let pool = Pool::with_config_source(DummyManager {}, rx).await.unwrap();
+            pool.close().await;
+            // The task applying configs drops its receiver when it stops
+            tokio::time::timeout(Duration::from_secs(1), tx.closed())
+                .await
+                .expect("still applying configs after the pool was closed");
+
+            let (mut tx, rx) = watch::channel(Config::default());
+            let pool = Pool::with_config_source(DummyManager {}, rx).await.unwrap();
+            drop(pool);
+            tokio::time::timeout(Duration::from_secs(1), tx.closed())
+                .await
+                .expect("still applying configs after the pool was dropped");
+        });
+    }
 }
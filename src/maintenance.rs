@@ -0,0 +1,203 @@
+//! Background maintenance of the connections held by a pool.
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::inner::ConnectionPool;
+use crate::manage_connection::ManageConnection;
+use crate::observer::EvictionReason;
+
+/// Delay before retrying the first failed connection attempt while replenishing the pool.
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+/// Upper bound for the delay between failed connection attempts while replenishing the pool.
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Spawns the maintenance task for `conn_pool`, if its config calls for one and it isn't running
+/// yet. The config is read again on every tick, so changes to it are picked up.
+///
+/// The task only holds a weak reference to the pool, so it stops on its next tick after the last
+/// `Pool` is dropped.
+pub fn spawn<C: ManageConnection>(conn_pool: &Arc<ConnectionPool<C>>) {
+    if conn_pool.max_lifetime().is_none()
+        && conn_pool.idle_timeout().is_none()
+        && conn_pool.health_check_interval().is_none()
+    {
+        return;
+    }
+    if !conn_pool.start_maintenance() {
+        return;
+    }
+
+    let mut interval = conn_pool.maintenance_interval();
+    let conn_pool = Arc::downgrade(conn_pool);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::delay_for(interval).await;
+            let conn_pool = match conn_pool.upgrade() {
+                Some(conn_pool) if !conn_pool.is_closed() => conn_pool,
+                _ => {
+                    debug!("maintenance: pool has been dropped or closed, stopping");
+                    return;
+                }
+            };
+            run(&conn_pool).await;
+            interval = conn_pool.maintenance_interval();
+        }
+    });
+}
+
+/// Runs a single round of maintenance on the pool.
+async fn run<C: ManageConnection>(conn_pool: &Arc<ConnectionPool<C>>) {
+    // Idle connections aren't handed out once they expire, but a quiet pool would keep them open
+    let expired = conn_pool.close_expired();
+    if expired > 0 {
+        debug!(
+            "maintenance: closed {} expired connections, connection count is now: {}",
+            expired,
+            conn_pool.conns.total()
+        );
+        spawn_replenish(conn_pool);
+    }
+
+    if let Some(idle_timeout) = conn_pool.idle_timeout() {
+        let reaped = conn_pool.reap(idle_timeout);
+        if reaped > 0 {
+            debug!(
+                "maintenance: closed {} idle connections, connection count is now: {}",
+                reaped,
+                conn_pool.conns.total()
+            );
+        }
+    }
+
+    if let Some(interval) = conn_pool.health_check_interval() {
+        health_check(conn_pool, interval).await;
+    }
+}
+
+/// Checks idle connections that have gone `interval` without being used or validated with
+/// `ManageConnection::is_valid`. Connections that fail (or take longer than
+/// `Config::health_check_timeout`) are closed and replaced.
+///
+/// The connections are checked at the same time, and each one that passes is put back as soon as
+/// it does.
+async fn health_check<C: ManageConnection>(conn_pool: &Arc<ConnectionPool<C>>, interval: Duration) {
+    let due = conn_pool.take_for_health_check(interval);
+    if due.is_empty() {
+        return;
+    }
+
+    let timeout = conn_pool.health_check_timeout();
+    let mut checks: FuturesUnordered<_> = due
+        .into_iter()
+        .map(|mut idle| async move {
+            let result = tokio::time::timeout(timeout, conn_pool.is_valid(&mut idle.conn)).await;
+            (idle, result)
+        })
+        .collect();
+    let mut failed = 0;
+    while let Some((idle, result)) = checks.next().await {
+        let err = match result {
+            Ok(Ok(())) => {
+                conn_pool.restore_idle(idle);
+                continue;
+            }
+            Ok(Err(err)) => err,
+            Err(_) => conn_pool.timed_out(),
+        };
+        debug!("health_check: connection failed, closing: {:?}", err);
+        conn_pool.evicted(&idle.conn, EvictionReason::FailedValidation);
+        conn_pool.conns.decrement();
+        failed += 1;
+    }
+
+    if failed > 0 {
+        debug!(
+            "health_check: closed {} connections, connection count is now: {}",
+            failed,
+            conn_pool.conns.total()
+        );
+        spawn_replenish(conn_pool);
+    }
+}
+
+/// Spawns a task that makes new connections until the pool is back up to `min_size`, backing off
+/// exponentially between failed attempts.
+///
+/// While there are waiters, connections are made on their behalf up to `max_size` instead. A new
+/// connection goes to the oldest waiter, and if connecting fails, that waiter fails with
+/// `InternalError::ConnectFailed` (the manager's error is only logged).
+///
+/// Only one such task runs at a time. If it's already running, this leaves it to that task, which
+/// keeps its backoff instead of another task retrying alongside it.
+///
+/// Like the maintenance task, this stops once the last `Pool` is dropped or the pool is closed.
+pub fn spawn_replenish<C: ManageConnection>(conn_pool: &Arc<ConnectionPool<C>>) {
+    if !conn_pool.start_replenish() {
+        return;
+    }
+
+    let conn_pool = Arc::downgrade(conn_pool);
+    tokio::spawn(async move {
+        let mut backoff = MIN_BACKOFF;
+        while let Some(conn_pool) = conn_pool.upgrade() {
+            if conn_pool.is_closed() {
+                return;
+            }
+
+            // The count is reserved up front, since others can make connections at the same time
+            let for_waiter = conn_pool.has_waiters();
+            if conn_pool
+                .conns
+                .safe_increment(replenish_target(&conn_pool, for_waiter))
+                .is_none()
+            {
+                conn_pool.stop_replenish();
+                // Anything that needed a connection since we looked left it to us, so look again
+                let for_waiter = conn_pool.has_waiters();
+                if conn_pool.conns.total() < replenish_target(&conn_pool, for_waiter)
+                    && conn_pool.start_replenish()
+                {
+                    continue;
+                }
+                return;
+            }
+
+            debug!(
+                "replenish: making a new connection (for waiter: {})",
+                for_waiter
+            );
+            match conn_pool.connect().await {
+                Ok(conn) => {
+                    backoff = MIN_BACKOFF;
+                    conn_pool.hand_off(conn_pool.new_live(conn));
+                }
+                Err(err) => {
+                    conn_pool.conns.decrement();
+                    debug!(
+                        "replenish: unable to connect, retrying in {:?}: {:?}",
+                        backoff, err
+                    );
+                    if for_waiter {
+                        conn_pool.fail_waiter();
+                    }
+                    drop(conn_pool);
+                    tokio::time::delay_for(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    });
+}
+
+/// How many connections `spawn_replenish` makes: up to `max_size` while there are waiters, and
+/// up to `min_size` otherwise.
+fn replenish_target<C: ManageConnection>(conn_pool: &ConnectionPool<C>, for_waiter: bool) -> usize {
+    if for_waiter {
+        conn_pool.max_size()
+    } else {
+        conn_pool.min_size()
+    }
+}
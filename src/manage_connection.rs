@@ -21,6 +21,7 @@
 // IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
 // CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+use crate::error::InternalError;
 use crate::Error as L337Error;
 use async_trait::async_trait;
 
@@ -43,13 +44,51 @@ pub trait ManageConnection: Send + Sync + 'static {
 
     /// Determines if the connection is still connected to the database.
     ///
-    /// Note that boxing is used here since impl Trait is not yet supported
-    /// within trait definitions.
-    async fn is_valid(&self, conn: Self::Connection) -> Result<(), L337Error<Self::Error>>;
+    /// The connection is borrowed, so the pool can keep using it if it's valid.
+    async fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), L337Error<Self::Error>>;
 
     /// Quick check to determine if the connection has broken
     fn has_broken(&self, conn: &mut Self::Connection) -> bool;
 
+    /// Called on every new connection before the pool uses it, e.g. to set up the session. If this
+    /// fails, the connection is discarded and the error is handled like a failed `connect`.
+    async fn after_connect(
+        &self,
+        _conn: &mut Self::Connection,
+    ) -> Result<(), L337Error<Self::Error>> {
+        Ok(())
+    }
+
+    /// Called on a connection right before `Pool::connection` hands it out. If this fails, the
+    /// connection is closed and `Pool::connection` tries another one.
+    async fn before_acquire(
+        &self,
+        _conn: &mut Self::Connection,
+    ) -> Result<(), L337Error<Self::Error>> {
+        Ok(())
+    }
+
+    /// Resets a connection that has been returned to the pool, e.g. by rolling back a transaction
+    /// that was left open, so the next user gets a clean connection. This runs before
+    /// `after_release`. If this fails, the connection is closed as if it were broken.
+    async fn recycle(&self, _conn: &mut Self::Connection) -> Result<(), L337Error<Self::Error>> {
+        Ok(())
+    }
+
+    /// Called on a connection that has been returned to the pool, before it's stored or handed to
+    /// a waiter. If this fails, the connection is closed as if it were broken.
+    async fn after_release(
+        &self,
+        _conn: &mut Self::Connection,
+    ) -> Result<(), L337Error<Self::Error>> {
+        Ok(())
+    }
+
     /// Produce an error representing a connection timeout.
-    fn timed_out(&self) -> L337Error<Self::Error>;
+    ///
+    /// Returned by `Pool::connection` when `Config::connection_timeout` elapses. Defaults to
+    /// an internal timeout error.
+    fn timed_out(&self) -> L337Error<Self::Error> {
+        L337Error::Internal(InternalError::Timeout)
+    }
 }
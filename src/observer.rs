@@ -0,0 +1,59 @@
+//! Hooks for observing what a pool is doing, e.g. to feed metrics.
+
+use std::fmt::Debug;
+use std::time::Duration;
+
+use crate::error::ConfigError;
+
+/// Why the pool closed a connection that wasn't broken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionReason {
+    /// The connection was idle for longer than `Config::idle_timeout`
+    IdleTimeout,
+    /// The connection outlived `Config::max_lifetime`
+    MaxLifetime,
+    /// The connection failed `ManageConnection::is_valid`, on checkout or in a health check
+    FailedValidation,
+    /// The pool was closed
+    PoolClosed,
+    /// The pool had more connections than its `max_size`, after it was lowered with
+    /// `Pool::set_max_size`
+    PoolShrunk,
+}
+
+/// Receives callbacks about what a pool is doing. Register one with `Config::observer`.
+///
+/// Every method does nothing by default, so implementations only need to override the ones they're
+/// interested in. Callbacks are made from within the pool, so they should return quickly.
+pub trait PoolObserver: Send + Sync + Debug {
+    /// A new connection was established, which took `latency`.
+    fn on_connect(&self, _latency: Duration) {}
+
+    /// Establishing a new connection failed after `latency`.
+    fn on_connect_failed(&self, _latency: Duration) {}
+
+    /// A connection was checked out with `Pool::connection`, after waiting `wait` for it.
+    fn on_checkout(&self, _wait: Duration) {}
+
+    /// A connection was returned to the pool.
+    fn on_checkin(&self) {}
+
+    /// A returned connection was found to be broken by `ManageConnection::has_broken`, and closed.
+    fn on_broken(&self) {}
+
+    /// A call to `Pool::connection` started waiting for a connection to be returned.
+    fn on_waiter_enqueued(&self) {}
+
+    /// A waiter stopped waiting, because it was given a connection or an error, or gave up.
+    fn on_waiter_dequeued(&self) {}
+
+    /// The pool closed a connection for `reason`.
+    fn on_evicted(&self, _reason: EvictionReason) {}
+
+    /// A connection was closed after being open for `lifetime`, whether it was broken or evicted.
+    fn on_closed(&self, _lifetime: Duration) {}
+
+    /// A config from `Pool::with_config_source` was rejected because of `error`, so the pool kept
+    /// its current config.
+    fn on_config_rejected(&self, _error: &ConfigError) {}
+}
@@ -0,0 +1,271 @@
+//! Pool metrics in the Prometheus text exposition format.
+//!
+//! Register a `PrometheusMetrics` as the pool's `Config::observer` to record histograms, then render
+//! it together with the pool's `State`:
+//!
+//! ```ignore
+//! let metrics = Arc::new(PrometheusMetrics::new());
+//! let config = Config {
+//!     name: Some("users".to_owned()),
+//!     observer: Some(metrics.clone()),
+//!     ..Default::default()
+//! };
+//! let pool = Pool::new(manager, config).await?;
+//! let body = metrics.render_pool(&pool).await;
+//! ```
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::manage_connection::ManageConnection;
+use crate::observer::PoolObserver;
+use crate::{Pool, State};
+
+/// Buckets, in seconds, for checkout waits and connect latencies.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// Buckets, in seconds, for how long connections live.
+const LIFETIME_BUCKETS: &[f64] = &[
+    1.0, 10.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 21600.0, 86400.0,
+];
+
+/// Label used for pools without a `Config::name`.
+const DEFAULT_POOL_NAME: &str = "default";
+
+/// A gauge's name, help text and how to read it from a pool's `State`.
+type Gauge = (&'static str, &'static str, fn(&State) -> usize);
+/// A histogram's name, help text and how to find it in a `PrometheusMetrics`.
+type HistogramFamily = (
+    &'static str,
+    &'static str,
+    fn(&PrometheusMetrics) -> &Histogram,
+);
+
+/// A cumulative histogram of durations.
+#[derive(Debug)]
+struct Histogram {
+    buckets: &'static [f64],
+    /// Cumulative count for each bucket, i.e. how many observations were `<=` its bound
+    counts: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Histogram {
+        Histogram {
+            buckets,
+            counts: buckets.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bound, count) in self.buckets.iter().zip(&self.counts) {
+            if secs <= *bound {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn write(&self, out: &mut String, name: &str, pool: &str) {
+        for (bound, count) in self.buckets.iter().zip(&self.counts) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{pool=\"{}\",le=\"{}\"}} {}",
+                name,
+                pool,
+                bound,
+                count.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)).as_secs_f64();
+        let _ = writeln!(
+            out,
+            "{}_bucket{{pool=\"{}\",le=\"+Inf\"}} {}",
+            name, pool, count
+        );
+        let _ = writeln!(out, "{}_sum{{pool=\"{}\"}} {}", name, pool, sum);
+        let _ = writeln!(out, "{}_count{{pool=\"{}\"}} {}", name, pool, count);
+    }
+}
+
+/// Records histograms of checkout waits, connect latencies and connection lifetimes, and renders
+/// them along with a pool's `State` in the Prometheus text exposition format.
+///
+/// Use it as the pool's `Config::observer`.
+#[derive(Debug)]
+pub struct PrometheusMetrics {
+    checkout_wait: Histogram,
+    connect_latency: Histogram,
+    connection_lifetime: Histogram,
+}
+
+impl Default for PrometheusMetrics {
+    fn default() -> Self {
+        PrometheusMetrics::new()
+    }
+}
+
+impl PrometheusMetrics {
+    /// Creates a new set of metrics with empty histograms
+    pub fn new() -> PrometheusMetrics {
+        PrometheusMetrics {
+            checkout_wait: Histogram::new(LATENCY_BUCKETS),
+            connect_latency: Histogram::new(LATENCY_BUCKETS),
+            connection_lifetime: Histogram::new(LIFETIME_BUCKETS),
+        }
+    }
+
+    /// Renders the metrics of `pool`, labelled with its `Config::name`.
+    pub async fn render_pool<C: ManageConnection>(&self, pool: &Pool<C>) -> String {
+        let state = pool.state().await;
+        self.render(pool.name().unwrap_or(DEFAULT_POOL_NAME), &state)
+    }
+
+    /// Renders these metrics and `state`, labelled with the pool name `name`.
+    pub fn render(&self, name: &str, state: &State) -> String {
+        render(&[(name, state, self)])
+    }
+}
+
+impl PoolObserver for PrometheusMetrics {
+    fn on_connect(&self, latency: Duration) {
+        self.connect_latency.observe(latency);
+    }
+
+    fn on_connect_failed(&self, latency: Duration) {
+        self.connect_latency.observe(latency);
+    }
+
+    fn on_checkout(&self, wait: Duration) {
+        self.checkout_wait.observe(wait);
+    }
+
+    fn on_closed(&self, lifetime: Duration) {
+        self.connection_lifetime.observe(lifetime);
+    }
+}
+
+/// Renders the metrics of several pools as a single exposition. Each entry is the pool's name,
+/// its `State` and the metrics registered as its observer.
+pub fn render(pools: &[(&str, &State, &PrometheusMetrics)]) -> String {
+    let pools: Vec<_> = pools
+        .iter()
+        .map(|(name, state, metrics)| (escape(name), *state, *metrics))
+        .collect();
+    let mut out = String::new();
+
+    let gauges: &[Gauge] = &[
+        (
+            "l337_pool_connections",
+            "Total number of connections, including those being established.",
+            |state| state.total,
+        ),
+        (
+            "l337_pool_idle_connections",
+            "Number of idle connections.",
+            |state| state.idle,
+        ),
+        (
+            "l337_pool_in_use_connections",
+            "Number of connections checked out of the pool.",
+            |state| state.in_use,
+        ),
+        (
+            "l337_pool_waiters",
+            "Number of callers waiting for a connection.",
+            |state| state.waiters,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for (pool, state, _) in &pools {
+            let _ = writeln!(out, "{}{{pool=\"{}\"}} {}", name, pool, value(state));
+        }
+    }
+
+    let histograms: &[HistogramFamily] = &[
+        (
+            "l337_pool_checkout_wait_seconds",
+            "Time spent waiting for a connection to be checked out.",
+            |metrics| &metrics.checkout_wait,
+        ),
+        (
+            "l337_pool_connect_seconds",
+            "Time taken to establish new connections, successful or not.",
+            |metrics| &metrics.connect_latency,
+        ),
+        (
+            "l337_pool_connection_lifetime_seconds",
+            "How long connections were open for when they were closed.",
+            |metrics| &metrics.connection_lifetime,
+        ),
+    ];
+    for (name, help, histogram) in histograms {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (pool, _, metrics) in &pools {
+            histogram(metrics).write(&mut out, name, pool);
+        }
+    }
+
+    out
+}
+
+/// Escapes a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_renders_gauges_and_histograms() {
+        let metrics = PrometheusMetrics::new();
+        metrics.on_checkout(Duration::from_millis(3));
+        metrics.on_checkout(Duration::from_secs(20));
+        let state = State {
+            total: 3,
+            idle: 1,
+            in_use: 2,
+            pending: 0,
+            waiters: 4,
+            min_size: 1,
+            max_size: 3,
+        };
+
+        let out = metrics.render("my \"pool\"", &state);
+        assert!(out.contains("# TYPE l337_pool_connections gauge\n"));
+        assert!(out.contains("l337_pool_connections{pool=\"my \\\"pool\\\"\"} 3\n"));
+        assert!(out.contains("l337_pool_waiters{pool=\"my \\\"pool\\\"\"} 4\n"));
+        assert!(out.contains("# TYPE l337_pool_checkout_wait_seconds histogram\n"));
+        assert!(out.contains(
+            "l337_pool_checkout_wait_seconds_bucket{pool=\"my \\\"pool\\\"\",le=\"0.001\"} 0\n"
+        ));
+        assert!(out.contains(
+            "l337_pool_checkout_wait_seconds_bucket{pool=\"my \\\"pool\\\"\",le=\"0.005\"} 1\n"
+        ));
+        assert!(out.contains(
+            "l337_pool_checkout_wait_seconds_bucket{pool=\"my \\\"pool\\\"\",le=\"+Inf\"} 2\n"
+        ));
+        assert!(
+            out.contains("l337_pool_checkout_wait_seconds_sum{pool=\"my \\\"pool\\\"\"} 20.003\n")
+        );
+        assert!(out.contains("l337_pool_connect_seconds_count{pool=\"my \\\"pool\\\"\"} 0\n"));
+    }
+}
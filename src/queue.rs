@@ -40,10 +40,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Instant;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::Notify;
+// tokio's `Instant` follows its clock, which tests can pause
+use tokio::time::Instant;
 
-use crossbeam::queue::SegQueue;
+use crate::config::IdleOrder;
 
 // Almost all of this file is directly from c3po: https://github.com/withoutboats/c3po/blob/08a6fde00c6506bacfe6eebe621520ee54b418bb/src/queue.rs
 
@@ -52,22 +57,53 @@ use crossbeam::queue::SegQueue;
 pub struct Live<T: Send> {
     pub conn: T,
     pub live_since: Instant,
+    /// When the connection was last returned to the pool (or made, if it hasn't been yet).
+    pub last_used: Instant,
+    /// When the connection last passed `ManageConnection::is_valid`, if it ever has.
+    pub last_validated: Option<Instant>,
+    /// How long the connection may live before it is closed. `None` lives forever.
+    pub lifetime: Option<Duration>,
 }
 
 impl<T: Send> Live<T> {
     pub fn new(conn: T) -> Live<T> {
+        Live::with_lifetime(conn, None)
+    }
+
+    pub fn with_lifetime(conn: T, lifetime: Option<Duration>) -> Live<T> {
+        let now = Instant::now();
         Live {
             conn,
-            live_since: Instant::now(),
+            live_since: now,
+            last_used: now,
+            last_validated: None,
+            lifetime,
+        }
+    }
+
+    /// When the connection was last known to be good, either from being used or from being
+    /// validated.
+    pub fn last_known_good(&self) -> Instant {
+        match self.last_validated {
+            Some(last_validated) => last_validated.max(self.last_used),
+            None => self.last_used,
+        }
+    }
+
+    /// Whether the connection has outlived its lifetime and should be closed.
+    pub fn is_expired(&self) -> bool {
+        match self.lifetime {
+            Some(lifetime) => self.live_since.elapsed() >= lifetime,
+            None => false,
         }
     }
 }
 
 /// An idle connection, carrying with it a record of how long it has been idle.
 #[derive(Debug)]
-struct Idle<T: Send> {
-    conn: Live<T>,
-    idle_since: Instant,
+pub struct Idle<T: Send> {
+    pub conn: Live<T>,
+    pub idle_since: Instant,
 }
 
 impl<T: Send> Idle<T> {
@@ -77,27 +113,137 @@ impl<T: Send> Idle<T> {
             idle_since: Instant::now(),
         }
     }
+
+    /// Whether the connection has gone `interval` without being used or validated.
+    fn is_due_for_health_check(&self, interval: Duration) -> bool {
+        self.conn.last_known_good().max(self.idle_since).elapsed() >= interval
+    }
+}
+
+/// Idle connections, indexed both by when they became idle and by when they were last known to be
+/// good, so a connection can be taken in any `IdleOrder` without scanning them all.
+#[derive(Debug)]
+struct IdleConns<C: Send> {
+    /// The connections, by when they became idle. The id tells apart connections that became idle
+    /// at the same instant.
+    by_idle_since: BTreeMap<(Instant, u64), Idle<C>>,
+    /// The `idle_since` of each connection, by when it was last known to be good. That can't
+    /// change while a connection is in the queue, so this never needs reordering.
+    by_last_known_good: BTreeMap<(Instant, u64), Instant>,
+    next_id: u64,
+}
+
+impl<C: Send> IdleConns<C> {
+    fn new() -> IdleConns<C> {
+        IdleConns {
+            by_idle_since: BTreeMap::new(),
+            by_last_known_good: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.by_idle_since.len()
+    }
+
+    fn push(&mut self, idle: Idle<C>) {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.by_last_known_good
+            .insert((idle.conn.last_known_good(), id), idle.idle_since);
+        self.by_idle_since.insert((idle.idle_since, id), idle);
+    }
+
+    fn remove(&mut self, key: (Instant, u64)) -> Option<Idle<C>> {
+        let idle = self.by_idle_since.remove(&key)?;
+        self.by_last_known_good
+            .remove(&(idle.conn.last_known_good(), key.1));
+        Some(idle)
+    }
+
+    /// Take the connection that comes first in `order`.
+    fn pop(&mut self, order: IdleOrder) -> Option<Idle<C>> {
+        let key = match order {
+            IdleOrder::Fifo => *self.by_idle_since.keys().next()?,
+            IdleOrder::Lifo => *self.by_idle_since.keys().next_back()?,
+            IdleOrder::MostRecentlyValidated => {
+                let (&(_, id), &idle_since) = self.by_last_known_good.iter().next_back()?;
+                (idle_since, id)
+            }
+        };
+        self.remove(key)
+    }
+
+    /// Take the connections that `f` picks, longest idle first.
+    fn remove_where(&mut self, mut f: impl FnMut(&Idle<C>) -> bool) -> Vec<Idle<C>> {
+        let keys: Vec<_> = self
+            .by_idle_since
+            .iter()
+            .filter(|(_, idle)| f(idle))
+            .map(|(&key, _)| key)
+            .collect();
+        keys.into_iter()
+            .filter_map(|key| self.remove(key))
+            .collect()
+    }
+
+    /// The connection that has been idle the longest, if there are any.
+    fn oldest(&self) -> Option<(&(Instant, u64), &Idle<C>)> {
+        self.by_idle_since.iter().next()
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = Idle<C>> {
+        self.by_last_known_good.clear();
+        std::mem::take(&mut self.by_idle_since).into_values()
+    }
 }
 
 /// A queue of idle connections which counts how many connections exist total
 /// (including those which are not in the queue.)
+///
+/// Idle connections are kept in the order they became idle, and in the order they were last known
+/// to be good, so they can be handed out in whichever `IdleOrder` the pool is configured with.
+///
+/// The counts only change while the idle connections are locked, so `counts` can read them all
+/// at once. Each can also be read on its own without the lock.
 #[derive(Debug)]
 pub struct Queue<C: Send> {
-    idle: SegQueue<Idle<C>>,
+    idle: Mutex<IdleConns<C>>,
     idle_count: AtomicUsize,
     total_count: AtomicUsize,
+    pending_count: AtomicUsize,
+    /// Notified when the total count drops to 0
+    emptied: Notify,
+}
+
+/// The counts of a `Queue`, read at the same time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Counts {
+    pub total: usize,
+    pub idle: usize,
+    pub pending: usize,
 }
 
 impl<C: Send> Queue<C> {
-    /// Construct an empty queue with a certain capacity
+    /// Construct an empty queue
     pub fn new() -> Queue<C> {
         Queue {
-            idle: SegQueue::new(),
+            idle: Mutex::new(IdleConns::new()),
             idle_count: AtomicUsize::new(0),
             total_count: AtomicUsize::new(0),
+            pending_count: AtomicUsize::new(0),
+            emptied: Notify::new(),
         }
     }
 
+    /// Lock the idle connections. Nothing that's done while holding the lock can panic, so it
+    /// can't be poisoned in a way that matters.
+    fn idle_conns(&self) -> MutexGuard<'_, IdleConns<C>> {
+        self.idle
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
     /// Count of idle connection in queue
     #[inline(always)]
     pub fn idle(&self) -> usize {
@@ -110,6 +256,38 @@ impl<C: Send> Queue<C> {
         self.total_count.load(Ordering::SeqCst)
     }
 
+    /// Count of connections being established
+    #[inline(always)]
+    pub fn pending(&self) -> usize {
+        self.pending_count.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once the total count is 0.
+    pub async fn emptied(&self) {
+        while self.total() > 0 {
+            self.emptied.notified().await;
+        }
+        // Pass the wakeup on, in case something else is waiting too
+        self.emptied.notify();
+    }
+
+    /// Take `count` off the total. Must be called while holding the lock on the idle connections.
+    fn remove_from_total(&self, count: usize) {
+        if count > 0 && self.total_count.fetch_sub(count, Ordering::SeqCst) == count {
+            self.emptied.notify();
+        }
+    }
+
+    /// All of the counts, consistent with each other.
+    pub fn counts(&self) -> Counts {
+        let _idle = self.idle_conns();
+        Counts {
+            total: self.total(),
+            idle: self.idle(),
+            pending: self.pending(),
+        }
+    }
+
     /// Push a new connection into the queue (this will increment
     /// the total connection count).
     pub fn new_conn(&self, conn: Live<C>) {
@@ -119,60 +297,171 @@ impl<C: Send> Queue<C> {
 
     /// Store a connection which has already been counted in the queue
     /// (this will NOT increment the total connection count).
-    pub fn store(&self, conn: Live<C>) {
+    pub fn store(&self, mut conn: Live<C>) {
+        conn.last_used = Instant::now();
+        let mut idle = self.idle_conns();
         self.idle_count.fetch_add(1, Ordering::SeqCst);
-        self.idle.push(Idle::new(conn));
+        idle.push(Idle::new(conn));
     }
 
-    /// Get the longest-idle connection from the queue.
-    pub fn get(&self) -> Option<Live<C>> {
-        self.idle.try_pop().map(|Idle { conn, .. }| {
-            self.idle_count.fetch_sub(1, Ordering::SeqCst);
-            conn
-        })
+    /// Get a connection from the queue, picked according to `order`. Connections that have
+    /// outlived their lifetime are passed to `on_expired` and closed (and no longer counted)
+    /// instead of being returned.
+    pub fn get(&self, order: IdleOrder, mut on_expired: impl FnMut(Live<C>)) -> Option<Live<C>> {
+        let mut expired = Vec::new();
+        let conn = {
+            let mut idle = self.idle_conns();
+            loop {
+                let conn = match idle.pop(order) {
+                    Some(Idle { conn, .. }) => conn,
+                    None => break None,
+                };
+                self.idle_count.fetch_sub(1, Ordering::SeqCst);
+                if conn.is_expired() {
+                    self.remove_from_total(1);
+                    expired.push(conn);
+                    continue;
+                }
+                break Some(conn);
+            }
+        };
+
+        // Close expired connections without holding the lock
+        for conn in expired {
+            on_expired(conn);
+        }
+        conn
     }
 
     /// Increment the connection count without pushing a connection into the
     /// queue.
     #[inline(always)]
     pub fn increment(&self) {
+        let _idle = self.idle_conns();
         self.total_count.fetch_add(1, Ordering::SeqCst);
     }
 
     /// Decrement the connection count
     #[inline(always)]
     pub fn decrement(&self) {
-        self.total_count.fetch_sub(1, Ordering::SeqCst);
+        let _idle = self.idle_conns();
+        self.remove_from_total(1);
         // this is commented out because it was cuasing an overflow. it's probably important that
         // this is actually run
         // self.idle_count.fetch_sub(1, Ordering::SeqCst);
     }
 
-    /// Increment the total number of connections safely, with guarantees that we won't increment
-    /// past `max`. This does block until max is reached, so don't pass a huge max size and expect
-    /// it to return quickly.
-    pub fn safe_increment(&self, max: usize) -> Option<()> {
-        let mut curr_count = self.total();
-        while curr_count < max {
-            match self.total_count.compare_exchange(
-                curr_count,
-                curr_count + 1,
-                Ordering::SeqCst,
-                Ordering::SeqCst,
-            ) {
-                Ok(_) => {
-                    // If we won the race, return that we did and stop trying
-                    return Some(());
+    /// Count a connection that's being established, until `remove_pending` is called.
+    pub fn add_pending(&self) {
+        let _idle = self.idle_conns();
+        self.pending_count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Stop counting a connection that was being established.
+    pub fn remove_pending(&self) {
+        let _idle = self.idle_conns();
+        self.pending_count.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Close all idle connections, passing each to `on_closed`. Returns the number of connections
+    /// closed.
+    pub fn clear(&self, on_closed: impl FnMut(Live<C>)) -> usize {
+        let cleared: Vec<_> = {
+            let mut idle = self.idle_conns();
+            self.idle_count.fetch_sub(idle.len(), Ordering::SeqCst);
+            self.remove_from_total(idle.len());
+            idle.drain().map(|idle| idle.conn).collect()
+        };
+
+        let count = cleared.len();
+        cleared.into_iter().for_each(on_closed);
+        count
+    }
+
+    /// Take the idle connections that have gone `interval` without being used or validated out of
+    /// the queue, so they can be health checked. They are still counted in the total.
+    pub fn take_for_health_check(&self, interval: Duration) -> Vec<Idle<C>> {
+        let mut idle = self.idle_conns();
+        let taken = idle.remove_where(|idle| idle.is_due_for_health_check(interval));
+        self.idle_count.fetch_sub(taken.len(), Ordering::SeqCst);
+
+        taken
+    }
+
+    /// Put a connection taken out with `take_for_health_check` back, without resetting how long
+    /// it has been idle.
+    pub fn restore(&self, restored: Idle<C>) {
+        let mut idle = self.idle_conns();
+        self.idle_count.fetch_add(1, Ordering::SeqCst);
+        idle.push(restored);
+    }
+
+    /// Close idle connections that have outlived their lifetime, passing each to `on_closed`.
+    /// Returns the number of connections closed.
+    pub fn close_expired(&self, on_closed: impl FnMut(Live<C>)) -> usize {
+        let expired: Vec<_> = {
+            let mut idle = self.idle_conns();
+            let expired = idle.remove_where(|idle| idle.conn.is_expired());
+            self.idle_count.fetch_sub(expired.len(), Ordering::SeqCst);
+            self.remove_from_total(expired.len());
+            expired.into_iter().map(|idle| idle.conn).collect()
+        };
+
+        let count = expired.len();
+        expired.into_iter().for_each(on_closed);
+        count
+    }
+
+    /// Close idle connections that have been idle for at least `idle_timeout`, without taking the
+    /// total number of connections below `min`. The longest-idle connections are closed first, and
+    /// each is passed to `on_closed`. Returns the number of connections closed.
+    pub fn reap(
+        &self,
+        idle_timeout: Duration,
+        min: usize,
+        on_closed: impl FnMut(Live<C>),
+    ) -> usize {
+        let reaped: Vec<_> = {
+            let mut idle = self.idle_conns();
+            let mut reaped = Vec::new();
+            while let Some((&key, entry)) = idle.oldest() {
+                if entry.idle_since.elapsed() < idle_timeout || self.total() <= min {
+                    break;
                 }
-                Err(_) => {
-                    // If didn't win the race, get the current count again and try to do the whole
-                    // thing again
-                    curr_count = self.total();
+                if let Some(entry) = idle.remove(key) {
+                    self.idle_count.fetch_sub(1, Ordering::SeqCst);
+                    self.remove_from_total(1);
+                    reaped.push(entry.conn);
                 }
             }
+            reaped
+        };
+
+        let count = reaped.len();
+        reaped.into_iter().for_each(on_closed);
+        count
+    }
+
+    /// Increment the total number of connections safely, with guarantees that we won't increment
+    /// past `max`.
+    pub fn safe_increment(&self, max: usize) -> Option<()> {
+        let _idle = self.idle_conns();
+        if self.total() >= max {
+            return None;
         }
+        self.total_count.fetch_add(1, Ordering::SeqCst);
+        Some(())
+    }
 
-        None
+    /// Decrement the total number of connections safely, with guarantees that we won't decrement
+    /// below `min`.
+    pub fn safe_decrement(&self, min: usize) -> Option<()> {
+        let _idle = self.idle_conns();
+        if self.total() <= min {
+            return None;
+        }
+        self.remove_from_total(1);
+        Some(())
     }
 }
 
@@ -203,13 +492,68 @@ mod tests {
     #[test]
     fn get() {
         let conns = Queue::new();
-        assert!(conns.get().is_none());
+        assert!(conns.get(IdleOrder::Fifo, drop).is_none());
+        conns.new_conn(Live::new(()));
+        assert!(conns.get(IdleOrder::Fifo, drop).is_some());
+        assert_eq!(conns.idle(), 0);
+        assert_eq!(conns.total(), 1);
+    }
+
+    #[test]
+    fn get_closes_expired() {
+        let conns = Queue::new();
+        conns.new_conn(Live::with_lifetime((), Some(Duration::from_secs(0))));
+        conns.new_conn(Live::new(()));
+        assert_eq!(conns.total(), 2);
+        let mut expired = 0;
+        assert!(conns.get(IdleOrder::Fifo, |_| expired += 1).is_some());
+        assert_eq!(expired, 1);
+        assert_eq!(conns.idle(), 0);
+        assert_eq!(conns.total(), 1);
+    }
+
+    #[test]
+    fn reap() {
+        let conns = Queue::new();
+        conns.new_conn(Live::new(()));
+        conns.new_conn(Live::new(()));
+        conns.new_conn(Live::new(()));
+        assert_eq!(conns.reap(Duration::from_secs(60), 1, drop), 0);
+        assert_eq!(conns.total(), 3);
+        assert_eq!(conns.reap(Duration::from_secs(0), 1, drop), 2);
+        assert_eq!(conns.idle(), 1);
+        assert_eq!(conns.total(), 1);
+    }
+
+    #[test]
+    fn clear() {
+        let conns = Queue::new();
+        conns.new_conn(Live::new(()));
         conns.new_conn(Live::new(()));
-        assert!(conns.get().is_some());
+        conns.increment();
+        assert_eq!(conns.clear(drop), 2);
         assert_eq!(conns.idle(), 0);
         assert_eq!(conns.total(), 1);
     }
 
+    #[test]
+    fn take_for_health_check_and_restore() {
+        let conns = Queue::new();
+        conns.new_conn(Live::new(()));
+        assert!(conns
+            .take_for_health_check(Duration::from_secs(60))
+            .is_empty());
+        let taken = conns.take_for_health_check(Duration::from_secs(0));
+        assert_eq!(taken.len(), 1);
+        assert_eq!(conns.idle(), 0);
+        assert_eq!(conns.total(), 1);
+        for idle in taken {
+            conns.restore(idle);
+        }
+        assert_eq!(conns.idle(), 1);
+        assert_eq!(conns.total(), 1);
+    }
+
     #[test]
     fn increment_and_decrement() {
         let conns: Queue<()> = Queue::new();
@@ -222,4 +566,107 @@ mod tests {
         assert_eq!(conns.total(), 0);
         assert_eq!(conns.idle(), 0);
     }
+
+    #[test]
+    fn get_in_order() {
+        let conns = Queue::new();
+        for id in 0..3 {
+            conns.new_conn(Live::new(id));
+        }
+        assert_eq!(conns.get(IdleOrder::Fifo, drop).unwrap().conn, 0);
+        assert_eq!(conns.get(IdleOrder::Lifo, drop).unwrap().conn, 2);
+        assert_eq!(conns.get(IdleOrder::Fifo, drop).unwrap().conn, 1);
+
+        let mut validated = Live::new(3);
+        validated.last_validated = Some(Instant::now() + Duration::from_secs(1));
+        conns.store(Live::new(4));
+        conns.store(validated);
+        conns.store(Live::new(5));
+        let conn = conns.get(IdleOrder::MostRecentlyValidated, drop).unwrap();
+        assert_eq!(conn.conn, 3);
+    }
+
+    #[test]
+    fn restore_keeps_idle_order() {
+        let conns = Queue::new();
+        conns.new_conn(Live::new(0));
+        let taken = conns.take_for_health_check(Duration::from_secs(0));
+        conns.new_conn(Live::new(1));
+        for idle in taken {
+            conns.restore(idle);
+        }
+        assert_eq!(conns.get(IdleOrder::Fifo, drop).unwrap().conn, 0);
+        assert_eq!(conns.get(IdleOrder::Fifo, drop).unwrap().conn, 1);
+    }
+
+    #[test]
+    fn get_most_recently_validated_after_restore() {
+        let conns = Queue::new();
+        conns.new_conn(Live::new(0));
+        conns.new_conn(Live::new(1));
+        let taken = conns.take_for_health_check(Duration::from_secs(0));
+        for mut idle in taken {
+            if idle.conn.conn == 0 {
+                idle.conn.last_validated = Some(Instant::now() + Duration::from_secs(1));
+            }
+            conns.restore(idle);
+        }
+        conns.store(Live::new(2));
+        let conn = conns.get(IdleOrder::MostRecentlyValidated, drop).unwrap();
+        assert_eq!(conn.conn, 0);
+        assert_eq!(conns.get(IdleOrder::Fifo, drop).unwrap().conn, 1);
+        assert_eq!(
+            conns
+                .get(IdleOrder::MostRecentlyValidated, drop)
+                .unwrap()
+                .conn,
+            2
+        );
+        assert_eq!(conns.idle(), 0);
+    }
+
+    #[test]
+    fn close_expired() {
+        let conns = Queue::new();
+        conns.new_conn(Live::with_lifetime(0, Some(Duration::from_secs(0))));
+        conns.new_conn(Live::new(1));
+        let mut closed = Vec::new();
+        assert_eq!(conns.close_expired(|conn| closed.push(conn.conn)), 1);
+        assert_eq!(closed, vec![0]);
+        assert_eq!(conns.idle(), 1);
+        assert_eq!(conns.total(), 1);
+    }
+
+    #[test]
+    fn counts() {
+        let conns = Queue::new();
+        conns.new_conn(Live::new(()));
+        conns.increment();
+        conns.add_pending();
+        assert_eq!(
+            conns.counts(),
+            Counts {
+                total: 2,
+                idle: 1,
+                pending: 1
+            }
+        );
+        conns.remove_pending();
+        assert_eq!(conns.pending(), 0);
+    }
+
+    #[test]
+    fn emptied() {
+        use futures::FutureExt;
+
+        let conns = Queue::new();
+        conns.new_conn(Live::new(()));
+        conns.increment();
+        let mut emptied = Box::pin(conns.emptied());
+        assert!((&mut emptied).now_or_never().is_none());
+        conns.decrement();
+        assert!((&mut emptied).now_or_never().is_none());
+        conns.clear(drop);
+        assert!(emptied.now_or_never().is_some());
+    }
 }